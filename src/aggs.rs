@@ -1,100 +1,172 @@
-use elasticsearch::SearchParts;
+use color_eyre::eyre::{eyre, WrapErr};
+use elasticsearch::{Elasticsearch, SearchParts};
 use serde_json::{json, Value};
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{sleep, Duration, Instant};
 
-use crate::elastic::create_client;
-use crate::elastic::Host;
-use crate::message::Message;
+use crate::elastic::{describe_response, SharedClient};
+use crate::message::{AggBucket, Message};
+use crate::supervisor::unix_now;
 
 // TODO use json! macro to create the query
 
+#[derive(Debug, Default)]
+pub struct AggStats {
+    pub pages: AtomicU64,
+    pub buckets: AtomicU64,
+    pub duplicate_paths: AtomicU64,
+    pub passes: AtomicU64,
+    pub last_pass_millis: AtomicU64,
+    // unix seconds, the worker counts as stalled when this stops moving
+    pub last_progress: AtomicU64,
+}
+
+impl AggStats {
+    fn progress(&self) {
+        self.last_progress.store(unix_now(), Ordering::Relaxed);
+    }
+}
+
 pub async fn get_aggs_entries_from_index(
-    es_host: Host,
+    client: SharedClient,
     index: &str,
     page_size: usize,
     agg_sleep: u64,
+    in_flight: Arc<Semaphore>,
     tx: mpsc::Sender<Message>,
+    stats: Arc<AggStats>,
 ) -> Result<(), color_eyre::Report> {
     loop {
-        let client = create_client(es_host.clone())?;
-
-        let mut after = String::new();
-
-        let mut hits = 1;
-
-        while hits > 0 {
-            hits = 0;
-
-            let json_query = generate_query(page_size, &after)?;
+        aggregate_index(
+            client.clone(),
+            index,
+            page_size,
+            in_flight.clone(),
+            tx.clone(),
+            stats.clone(),
+        )
+        .await?;
 
-            let value: serde_json::Value = serde_json::from_str(&json_query)?;
-
-            let response = client
-                .search(SearchParts::Index(&[index]))
-                .body(value)
-                .send()
-                .await?;
+        log::info!("Aggs task sleeping for {} seconds", agg_sleep);
+        //sleep for $agg_sleep seconds
+        sleep(Duration::from_secs(agg_sleep)).await;
+    }
+}
 
-            log::debug!("Response from ES: {:?}", response);
+// a single pass over the whole composite aggregation
+// every bucket with more than one record is sent as an Aggregate message,
+// each one needs an in-flight permit, so paging pauses while the limit is saturated
+pub async fn aggregate_index(
+    client: SharedClient,
+    index: &str,
+    page_size: usize,
+    in_flight: Arc<Semaphore>,
+    tx: mpsc::Sender<Message>,
+    stats: Arc<AggStats>,
+) -> Result<(), color_eyre::Report> {
+    let client = client.get();
 
-            let response_body = match response.json::<Value>().await {
-                Ok(body) => body,
-                Err(_) => continue,
-            };
+    let started = Instant::now();
+    let mut after = String::new();
 
-            let aggs =
-                match response_body["aggregations"]["unique_event_types"]["buckets"].as_array() {
-                    Some(aggs) => aggs,
-                    None => continue,
-                };
+    loop {
+        let (aggs, after_key) = match fetch_aggs_page(&client, index, page_size, &after).await? {
+            Some(page) => page,
+            // asking again right away would hammer a struggling cluster, the supervisor
+            // restarts the worker with backoff instead
+            None => return Err(eyre!("Failed to read aggregation page")),
+        };
+
+        stats.pages.fetch_add(1, Ordering::Relaxed);
+        stats.progress();
+        stats
+            .buckets
+            .fetch_add(aggs.len() as u64, Ordering::Relaxed);
+
+        for bucket in &aggs {
+            if bucket.doc_count > 1 {
+                stats.duplicate_paths.fetch_add(1, Ordering::Relaxed);
+                if in_flight.available_permits() == 0 {
+                    log::debug!("In-flight limit reached, pausing aggregation");
+                }
+                let permit = in_flight.clone().acquire_owned().await?;
 
-            for agg in aggs {
-                // let doc_count = agg["doc_count"].as_u64().unwrap();
-                let doc_count = match agg["doc_count"].as_u64() {
-                    Some(value) => value,
-                    None => {
-                        log::warn!("doc_count is not a u64 or does not exist");
-                        0
-                    }
+                let message = Message::Aggregate {
+                    bucket: bucket.clone(),
+                    permit,
                 };
 
-                if doc_count > 1 {
-                    let agg_clone = agg.clone();
-                    let _tx = tx.clone();
-                    tokio::spawn(async move {
-                        let message = Message::Aggregate {
-                            event_type: "Aggregate".to_string(),
-                            payload: agg_clone,
-                        };
-
-                        log::debug!("Sending message: {:?}", &message);
-
-                        // _tx.send(message).await.unwrap();
-                        if let Err(e) = _tx.send(message).await {
-                            log::error!("Failed to send message: {}", e);
-                        }
-                    });
-                } // if doc_count > 1
-                hits += 1;
-            }
+                log::debug!("Sending message: {:?}", &message);
 
-            if hits == 0 {
-                break;
-            }
+                if let Err(e) = tx.send(message).await {
+                    log::error!("Failed to send message: {}", e);
+                }
+            } // if doc_count > 1
+        }
 
-            after = response_body["aggregations"]["unique_event_types"]["after_key"]["file"]
-                .clone()
-                .to_string();
+        if aggs.is_empty() {
+            break;
         }
 
-        log::info!("Aggs task sleeping for {} seconds", agg_sleep);
-        //sleep for $agg_sleep seconds
-        sleep(Duration::from_secs(agg_sleep)).await;
+        after = after_key;
     }
+
+    stats.passes.fetch_add(1, Ordering::Relaxed);
+    stats.progress();
+    stats
+        .last_pass_millis
+        .store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+    Ok(())
+}
+
+// fetches one page of the composite aggregation, returns the buckets and the after key for the next page
+// None means the response could not be read
+pub async fn fetch_aggs_page(
+    client: &Elasticsearch,
+    index: &str,
+    page_size: usize,
+    after: &str,
+) -> Result<Option<(Vec<AggBucket>, String)>, color_eyre::Report> {
+    let json_query = generate_query(page_size, after)?;
+
+    let value: serde_json::Value = serde_json::from_str(&json_query)?;
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(value)
+        .send()
+        .await?;
+
+    log::debug!("Response from ES: {}", describe_response(&response));
+
+    let response_body = match response.json::<Value>().await {
+        Ok(body) => body,
+        Err(_) => return Ok(None),
+    };
+
+    let aggs = match response_body["aggregations"]["unique_event_types"]["buckets"].as_array() {
+        Some(aggs) => aggs,
+        None => return Ok(None),
+    };
+
+    let aggs = aggs
+        .iter()
+        .map(|agg| {
+            serde_json::from_value(agg.clone())
+                .wrap_err_with(|| format!("Malformed aggregation bucket: {}", agg))
+        })
+        .collect::<Result<Vec<AggBucket>, _>>()?;
+
+    // composite expects the whole after_key object ({"file": ...}) back, not just the value
+    let after_key = response_body["aggregations"]["unique_event_types"]["after_key"].to_string();
+
+    Ok(Some((aggs, after_key)))
 }
 
-fn generate_query(page_size: usize, after: &str) -> Result<String, color_eyre::Report> {
+pub fn generate_query(page_size: usize, after: &str) -> Result<String, color_eyre::Report> {
     let sources = json!([
         {
             "file": {
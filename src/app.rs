@@ -1,26 +1,32 @@
-use serde_json::Value;
-// use std::sync::{Arc, Mutex};
-// use std::sync::Arc;
-// use std::sync::atomic::{AtomicBool, Ordering};
-use tokio::sync::broadcast;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio::sync::{Mutex, Semaphore};
 use tokio::task::JoinHandle;
-// use std::time::Duration;
-// use std::cell::RefCell;
-
-// // use futures_util::future::future::FutureExt;
-// use futures_util::FutureExt;
-// use futures_util::task::noop_waker;
-
-use crate::aggs::get_aggs_entries_from_index;
-use crate::delete_records::delete_records_from_index;
-use crate::elastic::Host;
-use crate::latest::get_last_event_for_record;
-use crate::message::Message;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::aggs::{aggregate_index, get_aggs_entries_from_index, AggStats};
+use crate::config::{Config, DeleteMode};
+use crate::dead_letter::DeadLetters;
+use crate::delete_records::{
+    delete_records_from_index, parse_slices, DeleteOptions, DeleteReceiver, DeleteStats, Throttle,
+};
+use crate::elastic::{
+    watch_certificate, watch_node_health, watch_nodes, Host, HostConfig, SharedClient,
+};
+use crate::latest::{get_last_event_for_record, LookupStats};
+use crate::message::{DeleteDirective, Message};
+use crate::metrics::Metrics;
 use crate::parse_record::parse_record;
+use crate::probe::{Probes, AGGREGATION_WORKER, DELETE_WORKER};
+use crate::server;
+use crate::supervisor::Supervisor;
+
+// how often the certificate file is checked for changes
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct App {
-    pub es_host: Host,
+    pub client: SharedClient,
     pub should_quit: bool,
     pub should_suspend: bool,
     pub action_buffer_size: usize,
@@ -29,259 +35,551 @@ pub struct App {
     pub buffer_size: usize,
     pub del_timeout: u64,
     pub agg_sleep: u64,
+    pub dry_run: bool,
+    pub delete_mode: DeleteMode,
+    pub bulk_size: usize,
+    pub max_clauses: usize,
+    pub delete_retries: u32,
+    pub throttle: Throttle,
+    pub async_delete: bool,
+    pub task_file: String,
+    pub shutdown_timeout: u64,
+    pub max_in_flight: usize,
+    pub dead_letters: DeadLetters,
+    pub delete_stats: Arc<DeleteStats>,
+    pub agg_stats: Arc<AggStats>,
+    pub lookup_stats: Arc<LookupStats>,
+    // /metrics, /healthz and /readyz are only served when set
+    pub listen_addr: Option<SocketAddr>,
+    pub stall_timeout: u64,
+    pub supervisor: Supervisor,
 }
 
 impl App {
-    pub fn new(
-        es_host: Host,
-        action_buffer_size: usize,
-        index: &str,
-        page_size: usize,
-        buffer_size: usize,
-        del_timeout: u64,
-        agg_sleep: u64,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let condense = &config.condense;
         Ok(Self {
-            es_host,
+            client: SharedClient::new(Host::new(HostConfig::from(&config.elasticsearch)))?,
             should_quit: false,
             should_suspend: false,
-            action_buffer_size,
-            index: index.to_string(),
-            page_size,
-            buffer_size,
-            del_timeout,
-            agg_sleep,
+            action_buffer_size: condense.action_buffer,
+            index: condense.index.clone(),
+            page_size: condense.page_size,
+            buffer_size: condense.delete_buffer,
+            del_timeout: condense.delete_timeout,
+            agg_sleep: condense.aggregation_sleep,
+            dry_run: condense.dry_run,
+            delete_mode: condense.delete_mode,
+            bulk_size: condense.bulk_size,
+            max_clauses: condense.max_clauses,
+            delete_retries: condense.delete_retries,
+            throttle: Throttle {
+                slices: parse_slices(&condense.delete_slices)?,
+                requests_per_second: condense.requests_per_second,
+            },
+            async_delete: condense.async_delete,
+            task_file: condense.task_file.clone(),
+            shutdown_timeout: condense.shutdown_timeout,
+            max_in_flight: condense.max_in_flight,
+            dead_letters: DeadLetters::new(&condense.dead_letter_file),
+            delete_stats: Arc::new(DeleteStats::default()),
+            agg_stats: Arc::new(AggStats::default()),
+            lookup_stats: Arc::new(LookupStats::default()),
+            listen_addr: condense
+                .listen_addr
+                .as_deref()
+                .map(str::parse)
+                .transpose()?,
+            stall_timeout: condense.stall_timeout,
+            supervisor: Supervisor::new(),
         })
     }
 
     pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let (event_tx, mut event_rx) = mpsc::channel(self.action_buffer_size);
-        // let (delete_tx, delete_rx) = mpsc::channel(self.action_buffer_size);
-        let (delete_tx, _delete_rx) = broadcast::channel(self.action_buffer_size);
+        // bounded, parse tasks wait for room instead of directives getting lost
+        let (delete_tx, delete_rx) = mpsc::channel(self.action_buffer_size);
         log::info!(
             "Starting condensing app on index: {} with buffer size: {}",
             self.index,
             self.action_buffer_size
         );
 
-        let index = self.index.clone();
-        let page_size = self.page_size;
-        let buffer_size = self.buffer_size;
-        let del_timeout = self.del_timeout;
-        let agg_sleep = self.agg_sleep;
-
-        let mut handles = Vec::new();
-        let _index = index.to_string();
+        let server_handle = self.spawn_server(&event_tx).await?;
 
-        // -ARC bool is running-
-        // let is_running = Arc::new(AtomicBool::new(false));
+        // limits concurrent latest lookups and parses, see aggregate_index
+        let in_flight = Arc::new(Semaphore::new(self.max_in_flight));
 
-        let mut agg_handle: Option<tokio::task::JoinHandle<()>> = None;
+        // both workers are restarted with backoff by the supervisor when they fail
+        let agg_handle = self.spawn_aggregation_worker(&event_tx, in_flight);
+        let del_handle = self.spawn_delete_worker(Arc::new(Mutex::new(delete_rx)));
+        let cert_handle = tokio::spawn(watch_certificate(self.client.clone(), CERT_CHECK_INTERVAL));
+        let sniff_handle = tokio::spawn(watch_nodes(self.client.clone()));
+        let health_handle = tokio::spawn(watch_node_health(self.client.clone()));
 
-        let mut del_handle: Option<tokio::task::JoinHandle<()>> = None;
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
 
         loop {
-            // check if aggregation task is runnning, if not, restart it
-            if let Some(handle) = &agg_handle {
-                if handle.is_finished() {
-                    // The task has completed, you can start a new one
-                    agg_handle = None;
+            tokio::select! {
+                Some(event) = event_rx.recv() => {
+                    if let Err(e) = self.process_events(event, &event_tx, &delete_tx).await {
+                        log::error!("Failed to process events: {}", e);
+                    };
+                }
+                // reap finished tasks as they complete
+                Some(()) = self.supervisor.join_next() => {}
+                _ = &mut shutdown => {
+                    self.should_quit = true;
                 }
             }
 
-            if agg_handle.is_none() {
-                let _event_tx = event_tx.clone();
-                let _index_clone = index.to_string();
-                let _es_host = self.es_host.clone();
-
-                agg_handle = Some(tokio::spawn(async move {
-                    loop {
-                        match get_aggs_entries_from_index(
-                            _es_host.clone(),
-                            _index_clone.as_str(),
-                            page_size,
-                            agg_sleep,
-                            _event_tx.clone(),
-                        )
-                        .await
-                        {
-                            Ok(_) => break, // If the function succeeds, break the loop
-                            Err(e) => {
-                                log::error!(
-                                    "Failed to start get aggs entries from index task: {}",
-                                    e
-                                );
-                                // Optionally, you can add a delay before retrying
-                                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                            }
-                        };
-                    }
-                }));
+            if self.should_quit {
+                break;
             }
+            // if self.should_suspend {
+            //     return Ok(());
+            // }
+        }
+
+        // stop looking for new work, then finish what is already in flight
+        agg_handle.abort();
+        cert_handle.abort();
+        sniff_handle.abort();
+        health_handle.abort();
+
+        let result = self
+            .shutdown(&event_tx, &mut event_rx, delete_tx, del_handle)
+            .await;
+        if let Some(server_handle) = server_handle {
+            server_handle.abort();
+        }
+        result
+    }
 
-            // check if delete task is runnning, if not, restart it
-            if let Some(handle) = &del_handle {
-                if handle.is_finished() {
-                    // The task has completed, you can start a new one
-                    del_handle = None;
+    // a single full condensing pass: aggregate once, wait until every lookup and parse task is done,
+    // then let the delete task flush whatever is still buffered
+    pub async fn run_once(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (event_tx, mut event_rx) = mpsc::channel(self.action_buffer_size);
+        let (delete_tx, delete_rx) = mpsc::channel(self.action_buffer_size);
+        log::info!(
+            "Starting single condensing pass on index: {} with buffer size: {}",
+            self.index,
+            self.action_buffer_size
+        );
+
+        let server_handle = self.spawn_server(&event_tx).await?;
+
+        let del_handle = self.spawn_delete_worker(Arc::new(Mutex::new(delete_rx)));
+        let health_handle = tokio::spawn(watch_node_health(self.client.clone()));
+
+        let _event_tx = event_tx.clone();
+        let _index_clone = self.index.clone();
+        let _client = self.client.clone();
+        let page_size = self.page_size;
+        let in_flight = Arc::new(Semaphore::new(self.max_in_flight));
+        let agg_stats = self.agg_stats.clone();
+        let mut agg_handle = tokio::spawn(async move {
+            aggregate_index(
+                _client,
+                _index_clone.as_str(),
+                page_size,
+                in_flight,
+                _event_tx,
+                agg_stats,
+            )
+            .await
+        });
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        let agg_result = loop {
+            tokio::select! {
+                Some(event) = event_rx.recv() => {
+                    if let Err(e) = self.process_events(event, &event_tx, &delete_tx).await {
+                        log::error!("Failed to process events: {}", e);
+                    };
+                }
+                Some(()) = self.supervisor.join_next() => {}
+                result = &mut agg_handle => break Some(result),
+                _ = &mut shutdown => {
+                    agg_handle.abort();
+                    break None;
                 }
             }
+        };
 
-            if del_handle.is_none() {
-                let mut _delete_rx = delete_tx.subscribe();
-                let _index_clone = index.to_string();
-                let _es_host = self.es_host.clone();
-
-                del_handle = Some(tokio::spawn(async move {
-                    if let Err(e) = delete_records_from_index(
-                        _es_host.clone(),
-                        _index_clone.as_str(),
-                        buffer_size,
-                        del_timeout,
-                        _delete_rx,
-                    )
-                    .await
-                    {
-                        log::error!("Failed to start delete records from index task: {}", e)
-                    }
-                }));
+        // a failed pass still has lookups running and directives buffered, they are finished
+        // and deleted through the shutdown path before the error is returned
+        let mut pass_error = None;
+        match agg_result {
+            Some(Ok(Ok(()))) => {
+                self.should_quit = tokio::select! {
+                    _ = self.drain_events(&event_tx, &mut event_rx, &delete_tx) => false,
+                    _ = &mut shutdown => true,
+                };
             }
+            Some(Ok(Err(e))) => pass_error = Some(e.to_string()),
+            Some(Err(e)) => pass_error = Some(e.to_string()),
+            None => self.should_quit = true,
+        }
 
-            // -ARC bool is running-
-            // this was the first variant to check if the task is running, but while it was working, it needed a lot of extra imports
-            // using the onboard tokio handle tools (.is_finished()) felt more natural
-
-            // let is_running_clone = Arc::clone(&is_running);
-
-            // log::debug!("Starting get aggs entries from index task: {}", _index.as_str());
-
-            // let _event_tx = event_tx.clone();
-
-            // let _index_clone = index.to_string();
-            // let _es_host = self.es_host.clone();
-
-            // if !is_running_clone.load(Ordering::SeqCst) {
-            //     let _is_running_clone = Arc::clone(&is_running_clone);
-            //     let _agg_handle = tokio::spawn(async move {
-            //         _is_running_clone.store(true, Ordering::SeqCst);
-            //         loop {
-            //             // is_running_clone.store(true, Ordering::SeqCst);
-            //             match get_aggs_entries_from_index(
-            //                 _es_host.clone(),
-            //                 _index_clone.as_str(),
-            //                 page_size,
-            //                 agg_sleep,
-            //                 _event_tx.clone(),
-            //             )
-            //             .await
-            //             {
-            //                 Ok(_) => break, // If the function succeeds, break the loop
-            //                 Err(e) => {
-            //                     log::error!("Failed to start get aggs entries from index task: {}", e);
-            //                     // Optionally, you can add a delay before retrying
-            //                     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            //                 }
-            //             };
-            //         }
-            //         _is_running_clone.store(false, Ordering::SeqCst);
-            //     });
-            //     // handles.push(_agg_handle);
-            // }
+        if let Some(e) = &pass_error {
+            log::error!("Aggregation pass failed: {}", e);
+            self.should_quit = true;
+        }
 
-            // // let _ = _agg_handle.await;
-            // -ARC bool is running-
-
-            if let Some(event) = event_rx.recv().await {
-                let _es_host = self.es_host.clone();
-                if let Err(e) = self
-                    .process_events(
-                        _es_host,
-                        event,
-                        &event_tx,
-                        &delete_tx,
-                        &mut handles,
-                        _index.as_str(),
-                    )
-                    .await
-                {
-                    log::error!("Failed to process events: {}", e);
-                };
+        if self.should_quit {
+            let result = self
+                .shutdown(&event_tx, &mut event_rx, delete_tx, del_handle)
+                .await;
+            health_handle.abort();
+            if let Some(server_handle) = server_handle {
+                server_handle.abort();
+            }
+            if let Some(e) = pass_error {
+                return Err(format!("Aggregation pass failed: {}", e).into());
+            }
+            return result;
+        }
+
+        // closing the delete channel makes the delete task flush and return
+        drop(delete_tx);
+        del_handle.await?;
+
+        health_handle.abort();
+        if let Some(server_handle) = server_handle {
+            server_handle.abort();
+        }
+
+        self.delete_stats.log_summary();
+        log::info!("Condensing pass finished");
+
+        Ok(())
+    }
+
+    // binds listen_addr and serves /metrics and the probes until the handle is aborted,
+    // nothing without an address
+    async fn spawn_server(
+        &self,
+        event_tx: &mpsc::Sender<Message>,
+    ) -> Result<Option<JoinHandle<()>>, Box<dyn std::error::Error>> {
+        let Some(addr) = self.listen_addr else {
+            return Ok(None);
+        };
+        let listener = server::bind(addr).await?;
+        let metrics = Arc::new(Metrics {
+            aggs: self.agg_stats.clone(),
+            lookups: self.lookup_stats.clone(),
+            deletes: self.delete_stats.clone(),
+            supervisor: self.supervisor.stats(),
+            events: event_tx.downgrade(),
+        });
+        let probes = Arc::new(Probes {
+            client: self.client.clone(),
+            aggs: self.agg_stats.clone(),
+            deletes: self.delete_stats.clone(),
+            supervisor: self.supervisor.stats(),
+            aggregation_window: self.agg_sleep + self.stall_timeout,
+            delete_window: self.del_timeout + self.stall_timeout,
+        });
+        Ok(Some(tokio::spawn(server::serve(listener, metrics, probes))))
+    }
+
+    // processes queued events until every spawned task is done and nothing is left in the channel,
+    // every message is either still queued or owned by an unfinished task
+    async fn drain_events(
+        &mut self,
+        event_tx: &mpsc::Sender<Message>,
+        event_rx: &mut mpsc::Receiver<Message>,
+        delete_tx: &mpsc::Sender<DeleteDirective>,
+    ) {
+        loop {
+            if self.supervisor.is_empty() && event_rx.is_empty() {
+                return;
+            }
+
+            tokio::select! {
+                Some(event) = event_rx.recv() => {
+                    if let Err(e) = self.process_events(event, event_tx, delete_tx).await {
+                        log::error!("Failed to process events: {}", e);
+                    };
+                }
+                Some(()) = self.supervisor.join_next() => {}
             }
-            // if self.should_quit {
-            //     return Ok(());
-            // }
-            // if self.should_suspend {
-            //     return Ok(());
-            // }
         }
     }
 
+    // bounded by shutdown_timeout: let in-flight lookups finish, then have the delete task
+    // flush whatever is still buffered. the lookups get half of it at most, so the flush
+    // always keeps at least the other half
+    async fn shutdown(
+        &mut self,
+        event_tx: &mpsc::Sender<Message>,
+        event_rx: &mut mpsc::Receiver<Message>,
+        delete_tx: mpsc::Sender<DeleteDirective>,
+        del_handle: JoinHandle<()>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!(
+            "Shutting down, waiting up to {} seconds for running tasks",
+            self.shutdown_timeout
+        );
+
+        let budget = Duration::from_secs(self.shutdown_timeout);
+        let deadline = Instant::now() + budget;
+
+        let drain = self.drain_events(event_tx, event_rx, &delete_tx);
+        if timeout_at(deadline - budget / 2, drain).await.is_err() {
+            log::warn!(
+                "Shutdown drain deadline reached, aborting {} running tasks",
+                self.supervisor.len()
+            );
+            self.supervisor.abort_all();
+        }
+
+        // closing the delete channel makes the delete task flush and return
+        drop(delete_tx);
+
+        match timeout_at(deadline, del_handle).await {
+            Ok(Ok(())) => log::info!("Delete buffer flushed"),
+            Ok(Err(e)) => log::error!("Delete task failed during shutdown: {}", e),
+            Err(_) => {
+                log::warn!("Shutdown deadline reached before the delete buffer was flushed")
+            }
+        }
+
+        self.delete_stats.log_summary();
+        log::info!("Shutdown complete");
+
+        Ok(())
+    }
+
+    fn spawn_aggregation_worker(
+        &self,
+        event_tx: &mpsc::Sender<Message>,
+        in_flight: Arc<Semaphore>,
+    ) -> JoinHandle<()> {
+        let _event_tx = event_tx.clone();
+        let _index_clone = self.index.clone();
+        let _client = self.client.clone();
+        let page_size = self.page_size;
+        let agg_sleep = self.agg_sleep;
+        let stats = self.agg_stats.clone();
+
+        self.supervisor.spawn_worker(AGGREGATION_WORKER, move || {
+            let _event_tx = _event_tx.clone();
+            let _index_clone = _index_clone.clone();
+            let _client = _client.clone();
+            let _in_flight = in_flight.clone();
+            let _stats = stats.clone();
+
+            async move {
+                get_aggs_entries_from_index(
+                    _client,
+                    _index_clone.as_str(),
+                    page_size,
+                    agg_sleep,
+                    _in_flight,
+                    _event_tx,
+                    _stats,
+                )
+                .await
+                .map_err(|e| format!("Failed to get aggs entries from index: {}", e))
+            }
+        })
+    }
+
+    fn spawn_delete_worker(&self, delete_rx: DeleteReceiver) -> JoinHandle<()> {
+        let _index_clone = self.index.clone();
+        let _client = self.client.clone();
+        let options = DeleteOptions {
+            buffer_size: self.buffer_size,
+            timeout: self.del_timeout,
+            dry_run: self.dry_run,
+            mode: self.delete_mode,
+            bulk_size: self.bulk_size,
+            max_clauses: self.max_clauses,
+            max_retries: self.delete_retries,
+            throttle: self.throttle.clone(),
+            async_delete: self.async_delete,
+            task_file: self.task_file.clone(),
+        };
+        let stats = self.delete_stats.clone();
+
+        self.supervisor.spawn_worker(DELETE_WORKER, move || {
+            let _delete_rx = delete_rx.clone();
+            let _index_clone = _index_clone.clone();
+            let _client = _client.clone();
+            let _stats = stats.clone();
+            let options = options.clone();
+
+            async move {
+                delete_records_from_index(
+                    _client,
+                    _index_clone.as_str(),
+                    options,
+                    _stats,
+                    _delete_rx,
+                )
+                .await
+                .map_err(|e| format!("Failed to delete records from index: {}", e))
+            }
+        })
+    }
+
     async fn process_events(
         &mut self,
-        es_host: Host,
         event: Message,
         event_tx: &mpsc::Sender<Message>,
-        delete_tx: &broadcast::Sender<Value>,
-        handles: &mut Vec<JoinHandle<()>>,
-        index: &str,
+        delete_tx: &mpsc::Sender<DeleteDirective>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let _event_tx = event_tx.clone();
+        let client = self.client.clone();
         match event {
-            Message::Aggregate {
-                event_type: _event_type,
-                payload,
-            } => {
-                log::debug!(
-                    "Aggregate event received: {} with payload: {}",
-                    _event_type,
-                    payload
-                );
-                let _payload = payload.clone();
-                let _index = index.to_string();
-                let record = _payload["key"]["file"].to_owned();
-                let lastevent_handle = tokio::spawn(async move {
-                    // let _ = get_last_event_for_record(es_host, &_index, record.as_str().unwrap(), _event_tx).await;
-                    if let Some(record_str) = record.as_str() {
-                        let _ = get_last_event_for_record(es_host, &_index, record_str, _event_tx)
-                            .await;
-                    } else {
-                        log::error!("Failed to convert record to str");
-                    }
-                });
-                handles.push(lastevent_handle);
-            }
-            Message::LastRecord {
-                event_type: _event_type,
-                payload,
-            } => {
-                log::debug!(
-                    "LastRecord event received: {} with payload: {}",
-                    _event_type,
-                    payload
-                );
-                let _payload = payload.clone();
-                let parserecord_handle = tokio::spawn(async move {
-                    let _ = parse_record(_payload, _event_tx).await;
+            Message::Aggregate { bucket, permit } => {
+                log::debug!("Aggregate event received: {:?}", bucket);
+                let _index = self.index.clone();
+                let dead_letters = self.dead_letters.clone();
+                let stats = self.lookup_stats.clone();
+                self.supervisor.spawn_task(async move {
+                    let record = bucket.key.file;
+                    get_last_event_for_record(
+                        client,
+                        &_index,
+                        &record,
+                        permit,
+                        dead_letters,
+                        _event_tx,
+                        stats,
+                    )
+                    .await
+                    .map_err(|e| format!("Failed to get last event for {}: {}", record, e))
                 });
-                handles.push(parserecord_handle);
             }
-            Message::Delete {
-                event_type: _event_type,
-                payload,
-            } => {
-                log::debug!(
-                    "Delete event received: {} with payload: {}",
-                    _event_type,
-                    payload
-                );
+            Message::LastRecord { hit, raw, permit } => {
+                log::debug!("LastRecord event received: {:?}", hit);
+                let dead_letters = self.dead_letters.clone();
                 let _delete_tx = delete_tx.clone();
-                let deltx_handle = tokio::spawn(async move {
-                    log::debug!("Sending delete payload: {:?}", payload);
-                    let _ = _delete_tx.send(payload);
+                let stats = self.delete_stats.clone();
+                self.supervisor.spawn_task(async move {
+                    let result = parse_record(hit, raw, dead_letters, _delete_tx, stats)
+                        .await
+                        .map_err(|e| format!("Failed to parse record: {}", e));
+                    // the path is done with lookups and parsing
+                    drop(permit);
+                    result
                 });
-                handles.push(deltx_handle);
             }
         }
         Ok(())
     }
 }
+
+// resolves on SIGTERM (systemd stop) or SIGINT (ctrl-c)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+                    _ = terminate.recv() => log::info!("Received SIGTERM"),
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                log::info!("Received SIGINT");
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        log::info!("Received ctrl-c");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthMode;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // answers every request as a delete_by_query that removed one document, counting them
+    async fn stub_cluster() -> (String, Arc<AtomicU64>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let deletes = Arc::new(AtomicU64::new(0));
+        let counter = deletes.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut request = vec![0; 65536];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+                    if String::from_utf8_lossy(&request[..read]).contains("_delete_by_query") {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                    let body = r#"{"timed_out":false,"total":1,"deleted":1,"version_conflicts":0,"failures":[]}"#;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nX-Elastic-Product: Elasticsearch\r\n\
+                         content-type: application/json\r\ncontent-length: {}\r\n\
+                         connection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (url, deletes)
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_after_drain_timeout() {
+        let (url, deletes) = stub_cluster().await;
+
+        let mut config = Config::default();
+        config.elasticsearch.auth = Some(AuthMode::None);
+        config.elasticsearch.nodes = vec![url];
+        config.condense.shutdown_timeout = 2;
+        // nothing but the shutdown may flush
+        config.condense.delete_timeout = 3600;
+        let mut app = App::new(&config).unwrap();
+
+        let (event_tx, mut event_rx) = mpsc::channel(8);
+        let (delete_tx, delete_rx) = mpsc::channel(8);
+        let del_handle = app.spawn_delete_worker(Arc::new(Mutex::new(delete_rx)));
+
+        delete_tx
+            .send(DeleteDirective {
+                file_path: "/srv/data".to_string(),
+                event_type: "deletion".to_string(),
+                keep: None,
+                cascade: false,
+                timestamp: "2024-03-27T18:02:36.021Z".to_string(),
+                attempts: 0,
+            })
+            .await
+            .unwrap();
+
+        // a lookup that never finishes takes up the whole drain
+        app.supervisor
+            .spawn_task(std::future::pending::<Result<(), String>>());
+
+        app.shutdown(&event_tx, &mut event_rx, delete_tx, del_handle)
+            .await
+            .unwrap();
+
+        assert_eq!(deletes.load(Ordering::Relaxed), 1);
+        assert_eq!(app.delete_stats.deleted.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,270 @@
+use elasticsearch::{BulkOperation, BulkParts, Elasticsearch, OpenPointInTimeParts, SearchParts};
+use serde_json::{json, Value};
+
+use crate::delete_records::{generate_query, DeleteStats};
+use crate::message::DeleteDirective;
+
+// exact-id alternative to delete_by_query: every document a directive would remove is resolved
+// to its _index / _id through a point in time and deleted with _bulk, one search page per request
+
+const PIT_KEEP_ALIVE: &str = "1m";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BulkDeleteStats {
+    pub deleted: u64,
+    // already gone, e.g. removed by an earlier flush
+    pub not_found: u64,
+    pub failed: u64,
+}
+
+// returns the totals and the directives that were not (completely) deleted
+pub async fn bulk_delete_directives<'a>(
+    client: &Elasticsearch,
+    index: &str,
+    directives: impl IntoIterator<Item = &'a DeleteDirective>,
+    batch_size: usize,
+    delete_stats: &DeleteStats,
+) -> Result<(BulkDeleteStats, Vec<&'a DeleteDirective>), Box<dyn std::error::Error>> {
+    let mut pit_id = open_pit(client, index).await?;
+    let mut stats = BulkDeleteStats::default();
+    let mut failed = vec![];
+
+    let mut directives = directives.into_iter();
+    for directive in directives.by_ref() {
+        let before = stats;
+
+        // the error is kept as a string, it does not live across the next await otherwise
+        let result = delete_directive(
+            client,
+            &mut pit_id,
+            directive,
+            batch_size,
+            &mut stats,
+            delete_stats,
+        )
+        .await
+        .map_err(|e| e.to_string());
+        if let Err(e) = result {
+            // the point in time is most likely unusable, the rest is retried with the next flush
+            log::error!("Bulk delete for {} failed: {}", directive.file_path, e);
+            failed.push(directive);
+            break;
+        }
+
+        log::info!(
+            "Deleted {} documents for {} ({} not found, {} failed)",
+            stats.deleted - before.deleted,
+            directive.file_path,
+            stats.not_found - before.not_found,
+            stats.failed - before.failed
+        );
+
+        if stats.failed > before.failed {
+            failed.push(directive);
+        }
+    }
+    failed.extend(directives);
+
+    // the point in time expires on its own, failing to close it early is not fatal
+    if let Err(e) = close_pit(client, &pit_id).await {
+        log::warn!("Failed to close point in time: {}", e);
+    }
+
+    Ok((stats, failed))
+}
+
+async fn delete_directive(
+    client: &Elasticsearch,
+    pit_id: &mut String,
+    directive: &DeleteDirective,
+    batch_size: usize,
+    stats: &mut BulkDeleteStats,
+    delete_stats: &DeleteStats,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let query = generate_query([directive])?;
+
+    let mut search_after: Option<Value> = None;
+
+    loop {
+        let mut body = json!({
+            "size": batch_size,
+            "_source": false,
+            "track_total_hits": false,
+            "query": query["query"],
+            "pit": {"id": pit_id, "keep_alive": PIT_KEEP_ALIVE},
+            "sort": [{"_shard_doc": "asc"}]
+        });
+        if let Some(after) = &search_after {
+            body["search_after"] = after.clone();
+        }
+
+        let response = client.search(SearchParts::None).body(body).send().await?;
+
+        let response_body = response.json::<Value>().await?;
+
+        // the point in time id may change between searches
+        if let Some(id) = response_body["pit_id"].as_str() {
+            *pit_id = id.to_string();
+        }
+
+        let hits = response_body["hits"]["hits"]
+            .as_array()
+            .ok_or_else(|| format!("Unexpected search response: {}", response_body))?;
+
+        if hits.is_empty() {
+            return Ok(());
+        }
+
+        let targets = delete_targets(hits, stats);
+        if !targets.is_empty() {
+            bulk_delete(client, &targets, stats).await?;
+        }
+        delete_stats.progress();
+
+        if hits.len() < batch_size {
+            return Ok(());
+        }
+
+        search_after = hits.last().map(|hit| hit["sort"].clone());
+    }
+}
+
+// the _index / _id of every hit, a hit missing either can not be deleted and counts as failed
+// so the directive is retried instead of reported as done
+fn delete_targets<'h>(hits: &'h [Value], stats: &mut BulkDeleteStats) -> Vec<(&'h str, &'h str)> {
+    let mut targets = vec![];
+    for hit in hits {
+        match (hit["_index"].as_str(), hit["_id"].as_str()) {
+            (Some(index), Some(id)) => targets.push((index, id)),
+            _ => {
+                log::warn!("Search hit without _index or _id, not deleted: {}", hit);
+                stats.failed += 1;
+            }
+        }
+    }
+    targets
+}
+
+async fn bulk_delete(
+    client: &Elasticsearch,
+    targets: &[(&str, &str)],
+    stats: &mut BulkDeleteStats,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let operations: Vec<BulkOperation<()>> = targets
+        .iter()
+        .map(|(index, id)| BulkOperation::delete(*id).index(*index).into())
+        .collect();
+
+    let response = client.bulk(BulkParts::None).body(operations).send().await?;
+
+    let response_body = response.json::<Value>().await?;
+
+    record_bulk_items(&response_body, stats)
+}
+
+// counts the per-item results of a _bulk delete response and logs every failed item
+fn record_bulk_items(
+    response_body: &Value,
+    stats: &mut BulkDeleteStats,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let items = response_body["items"]
+        .as_array()
+        .ok_or_else(|| format!("Unexpected bulk response: {}", response_body))?;
+
+    for item in items {
+        let item = &item["delete"];
+        match item["status"].as_u64() {
+            Some(200) => stats.deleted += 1,
+            Some(404) => {
+                log::debug!("Already deleted: {}/{}", item["_index"], item["_id"]);
+                stats.not_found += 1;
+            }
+            status => {
+                log::warn!(
+                    "Failed to delete {}/{}: status {:?}: {}",
+                    item["_index"],
+                    item["_id"],
+                    status,
+                    item["error"]
+                );
+                stats.failed += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn open_pit(
+    client: &Elasticsearch,
+    index: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let response = client
+        .open_point_in_time(OpenPointInTimeParts::Index(&[index]))
+        .keep_alive(PIT_KEEP_ALIVE)
+        .send()
+        .await?;
+
+    let response_body = response.json::<Value>().await?;
+
+    match response_body["id"].as_str() {
+        Some(id) => Ok(id.to_string()),
+        None => Err(format!("Failed to open point in time: {}", response_body).into()),
+    }
+}
+
+async fn close_pit(client: &Elasticsearch, pit_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    client
+        .close_point_in_time()
+        .body(json!({"id": pit_id}))
+        .send()
+        .await?
+        .error_for_status_code()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_bulk_items_counts_each_outcome() {
+        let response = json!({
+            "took": 3,
+            "errors": true,
+            "items": [
+                {"delete": {"_index": "a", "_id": "1", "status": 200, "result": "deleted"}},
+                {"delete": {"_index": "a", "_id": "2", "status": 200, "result": "deleted"}},
+                {"delete": {"_index": "a", "_id": "3", "status": 404, "result": "not_found"}},
+                {"delete": {"_index": "a", "_id": "4", "status": 429, "error": {"type": "es_rejected_execution_exception"}}}
+            ]
+        });
+
+        let mut stats = BulkDeleteStats::default();
+        record_bulk_items(&response, &mut stats).unwrap();
+
+        assert_eq!(
+            stats,
+            BulkDeleteStats {
+                deleted: 2,
+                not_found: 1,
+                failed: 1
+            }
+        );
+        assert!(record_bulk_items(&json!({"error": "boom"}), &mut stats).is_err());
+    }
+
+    #[test]
+    fn test_hits_without_index_or_id_count_as_failed() {
+        let hits = vec![
+            json!({"_index": "a", "_id": "1", "sort": [1]}),
+            json!({"_id": "2", "sort": [2]}),
+            json!({"_index": "a", "sort": [3]}),
+        ];
+
+        let mut stats = BulkDeleteStats::default();
+        assert_eq!(delete_targets(&hits, &mut stats), vec![("a", "1")]);
+        assert_eq!(stats.failed, 2);
+    }
+}
@@ -0,0 +1,181 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use crate::config::{AuthMode, DeleteMode};
+
+// every flag is optional, when set it overrides the value from the config file and the environment / .env file
+// with no subcommand the condenser runs continuously, like before
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Condenses filesystem events in an elasticsearch index"
+)]
+pub struct Cli {
+    /// TOML config file [env: CONDENSE_CONFIG]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Condense the index continuously (default)
+    Run(RunArgs),
+    /// Run a single full condensing pass, then exit
+    Once(RunArgs),
+    /// Report how many paths still have more than one record
+    Health(HealthArgs),
+    /// Write a deletion plan to disk without deleting anything
+    Plan(PlanArgs),
+    /// Execute a previously written deletion plan
+    Apply(PlanArgs),
+    /// Print the resolved configuration and exit
+    CheckConfig(RunArgs),
+    /// Check connectivity, server version, index mapping and privileges, then exit
+    Preflight(RunArgs),
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct ConnectionArgs {
+    /// Index (pattern) to condense [env: CONDENSE_INDEX]
+    #[arg(long)]
+    pub index: Option<String>,
+    /// Elasticsearch host [env: ES_IP]
+    #[arg(long)]
+    pub es_ip: Option<String>,
+    /// Elasticsearch port [env: ES_PORT]
+    #[arg(long)]
+    pub es_port: Option<u16>,
+    /// Comma separated node URLs, used instead of --es-ip / --es-port [env: ES_NODES]
+    #[arg(long, value_delimiter = ',')]
+    pub es_nodes: Option<Vec<String>>,
+    /// Elastic Cloud ID, used instead of the host or node list [env: ES_CLOUD_ID]
+    #[arg(long)]
+    pub es_cloud_id: Option<String>,
+    /// Periodically replace the node list with the http nodes the cluster reports [env: ES_SNIFF]
+    #[arg(long)]
+    pub es_sniff: bool,
+    /// Authentication mode, inferred from the configured credentials when not set [env: ES_AUTH]
+    #[arg(long, value_enum)]
+    pub es_auth: Option<AuthMode>,
+    /// Id of the API key, ES_API_KEY alone is taken as the encoded key [env: ES_API_KEY_ID]
+    #[arg(long)]
+    pub es_api_key_id: Option<String>,
+    /// Elasticsearch user [env: ES_USER]
+    #[arg(long)]
+    pub es_user: Option<String>,
+    /// CA certificate or bundle of the cluster, the system trust store is used without [env: CERT_PATH]
+    #[arg(long)]
+    pub cert_path: Option<String>,
+    /// Do not verify the cluster's TLS certificate at all [env: ES_INSECURE]
+    #[arg(long)]
+    pub es_insecure: bool,
+    /// Client certificate (chain) for mutual TLS [env: ES_CLIENT_CERT]
+    #[arg(long)]
+    pub client_cert_path: Option<String>,
+    /// Private key of the client certificate [env: ES_CLIENT_KEY]
+    #[arg(long)]
+    pub client_key_path: Option<String>,
+    /// Directory for the log file [env: CONDENSE_LOG_PATH]
+    #[arg(long)]
+    pub log_path: Option<String>,
+    /// Also log to the console [env: CONDENSE_LOG_TO_CONSOLE]
+    #[arg(long)]
+    pub log_to_console: Option<bool>,
+    /// Composite aggregation page size [env: CONDENSE_PAGE_SIZE]
+    #[arg(long)]
+    pub page_size: Option<usize>,
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+    /// Event channel size [env: CONDENSE_ACTION_BUFFER]
+    #[arg(long)]
+    pub action_buffer: Option<usize>,
+    /// Paths to buffer before sending a delete to elasticsearch [env: CONDENSE_DELETE_BUFFER]
+    #[arg(long)]
+    pub delete_buffer: Option<usize>,
+    /// Seconds to wait before flushing a partially filled delete buffer [env: CONDENSE_DELETE_TIMEOUT]
+    #[arg(long)]
+    pub delete_timeout: Option<u64>,
+    /// Seconds to sleep between aggregation runs [env: CONDENSE_AGGREGATION_SLEEP]
+    #[arg(long)]
+    pub aggregation_sleep: Option<u64>,
+    /// Only count what would be deleted [env: CONDENSE_DRY_RUN]
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Seconds to wait for running tasks and the final delete flush on shutdown [env: CONDENSE_SHUTDOWN_TIMEOUT]
+    #[arg(long)]
+    pub shutdown_timeout: Option<u64>,
+    /// Maximum number of paths being looked up and parsed at the same time [env: CONDENSE_MAX_IN_FLIGHT]
+    #[arg(long)]
+    pub max_in_flight: Option<usize>,
+    /// File for records that were rejected before reaching the delete buffer [env: CONDENSE_DEAD_LETTER_FILE]
+    #[arg(long)]
+    pub dead_letter_file: Option<String>,
+    /// How to delete buffered paths: query (delete_by_query) or bulk (exact ids) [env: CONDENSE_DELETE_MODE]
+    #[arg(long, value_enum)]
+    pub delete_mode: Option<DeleteMode>,
+    /// Documents per search page and _bulk request in bulk delete mode [env: CONDENSE_BULK_SIZE]
+    #[arg(long)]
+    pub bulk_size: Option<usize>,
+    /// Clause budget of a single delete query, keep it at or below the cluster's max_clause_count [env: CONDENSE_MAX_CLAUSES]
+    #[arg(long)]
+    pub max_clauses: Option<usize>,
+    /// How often a path whose delete failed is requeued before it is given up [env: CONDENSE_DELETE_RETRIES]
+    #[arg(long)]
+    pub delete_retries: Option<u32>,
+    /// Submit delete_by_query as a background task and poll it through the tasks API [env: CONDENSE_ASYNC_DELETE]
+    #[arg(long)]
+    pub async_delete: bool,
+    /// Slices of a delete_by_query: auto or a number [env: CONDENSE_DELETE_SLICES]
+    #[arg(long)]
+    pub delete_slices: Option<String>,
+    /// Throttle of a delete_by_query in documents per second, unthrottled if unset [env: CONDENSE_REQUESTS_PER_SECOND]
+    #[arg(long)]
+    pub requests_per_second: Option<u32>,
+    /// File for delete tasks that are still running, resumed on the next start [env: CONDENSE_TASK_FILE]
+    #[arg(long)]
+    pub task_file: Option<String>,
+    /// Start without checking connectivity, version, mapping and privileges first [env: CONDENSE_PREFLIGHT=false]
+    #[arg(long)]
+    pub skip_preflight: bool,
+    /// Address (ip:port) to serve /metrics, /healthz and /readyz on, not served when unset [env: CONDENSE_LISTEN_ADDR]
+    #[arg(long)]
+    pub listen_addr: Option<String>,
+    /// Seconds a worker may go without progress, on top of its own sleep / timeout, before /healthz fails [env: CONDENSE_STALL_TIMEOUT]
+    #[arg(long)]
+    pub stall_timeout: Option<u64>,
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct HealthArgs {
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+    /// How many of the worst paths to list
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(Args, Debug, Default, Clone)]
+pub struct PlanArgs {
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+    /// Plan file to write or apply [env: CONDENSE_PLAN_FILE]
+    #[arg(long)]
+    pub plan_file: Option<String>,
+}
@@ -0,0 +1,64 @@
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// records that never reach the delete buffer end up here, one json object per line
+// with the reason and the hit as elasticsearch returned it
+#[derive(Debug, Clone)]
+pub struct DeadLetters {
+    path: PathBuf,
+    // opened on the first rejected record, most runs never write one
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl DeadLetters {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn write(&self, reason: &str, hit: &Value) -> Result<(), Box<dyn std::error::Error>> {
+        log::warn!(
+            "Rejected record, writing it to {}: {}",
+            self.path.display(),
+            reason
+        );
+
+        let line = json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "reason": reason,
+            "hit": hit,
+        });
+
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| "Dead-letter file lock poisoned")?;
+
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .map_err(|e| {
+                        format!(
+                            "Failed to open dead-letter file {}: {}",
+                            self.path.display(),
+                            e
+                        )
+                    })?,
+            );
+        }
+
+        if let Some(file) = file.as_mut() {
+            writeln!(file, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,128 +1,625 @@
 // use serde::Serialize;
-use elasticsearch::DeleteByQueryParts;
+use elasticsearch::params::{Conflicts, Slices};
+use elasticsearch::{CountParts, DeleteByQueryParts, Elasticsearch};
+use serde::Deserialize;
 use serde_json::json;
 use serde_json::Value;
-use std::collections::HashSet;
-use tokio::sync::broadcast;
-// use tokio::sync::mpsc;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::{sleep, Duration};
 
-use crate::elastic::create_client;
-use crate::elastic::Host;
+use crate::bulk_delete::bulk_delete_directives;
+use crate::config::DeleteMode;
+use crate::delete_tasks::{wait_for_task, PendingTask, TaskStore};
+use crate::elastic::SharedClient;
+use crate::message::DeleteDirective;
+use crate::supervisor::unix_now;
+
+// how the delete task buffers and flushes directives
+#[derive(Debug, Clone)]
+pub struct DeleteOptions {
+    pub buffer_size: usize,
+    pub timeout: u64,
+    pub dry_run: bool,
+    pub mode: DeleteMode,
+    pub bulk_size: usize,
+    pub max_clauses: usize,
+    pub max_retries: u32,
+    pub throttle: Throttle,
+    // submit delete_by_query as a task and poll it instead of waiting on the request
+    pub async_delete: bool,
+    pub task_file: String,
+}
+
+// slicing and throttling of delete_by_query requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct Throttle {
+    pub slices: Slices,
+    pub requests_per_second: Option<u32>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            slices: Slices::Count(1),
+            requests_per_second: None,
+        }
+    }
+}
+
+// "auto" or the number of slices
+pub fn parse_slices(value: &str) -> Result<Slices, String> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(Slices::Auto);
+    }
+    match value.parse::<i32>() {
+        Ok(count) if (1..=1024).contains(&count) => Ok(Slices::Count(count)),
+        _ => Err(format!("expected auto or 1 to 1024, got {:?}", value)),
+    }
+}
+
+// upper bound of the clauses one directive adds to a delete query:
+// its share of the per-timestamp bool, the range, the terms and a prefix when it cascades
+fn clause_cost(directive: &DeleteDirective) -> usize {
+    if directive.cascade {
+        4
+    } else {
+        3
+    }
+}
+
+// the outer bool and the must_not terms of every delete query
+const QUERY_CLAUSES: usize = 2;
+
+// the single consumer end of the delete queue, shared so a restarted delete worker picks up
+// where the failed one stopped instead of losing what is still queued
+pub type DeleteReceiver = Arc<Mutex<mpsc::Receiver<DeleteDirective>>>;
 
 pub async fn delete_records_from_index(
-    es_host: Host,
+    client: SharedClient,
     index: &str,
-    buffer_size: usize,
-    timeout: u64,
-    mut delete_rx: broadcast::Receiver<Value>,
+    options: DeleteOptions,
+    stats: Arc<DeleteStats>,
+    delete_rx: DeleteReceiver,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut file_paths = HashSet::new();
-    let mut records = HashSet::new();
+    let mut delete_rx = delete_rx.lock().await;
+
+    let DeleteOptions {
+        buffer_size,
+        timeout,
+        ..
+    } = options;
+
+    // one directive per path, a newer directive for the same path replaces the buffered one
+    let mut directives = HashMap::new();
 
-    log::info!("Delete records from index: {}", index);
+    if options.async_delete && !options.dry_run {
+        resume_tasks(&mut directives, &client, &options, &stats).await?;
+    }
+
+    if options.dry_run {
+        log::info!("Dry run: nothing will be deleted from index: {}", index);
+    } else {
+        log::info!(
+            "Delete records from index: {} ({} mode)",
+            index,
+            options.mode
+        );
+    }
     loop {
         tokio::select! {
             // Wait for a new record or timeout
 
             result = delete_rx.recv() => {
                 match result {
-                    Ok(record) => {
-                        log::debug!("Received record: {:?}", record);
-                        let file_path = record
-                            .get("file_path")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("empty_file_path")
-                            .to_string();
-
-                        let record_id = record
-                            .get("record_id")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("empty_record_id")
-                            .to_string();
-
-                        let record_index = record
-                            .get("record_index")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("empty_record_index")
-                            .to_string();
-
-                        file_paths.insert(file_path);
-                        records.insert((record_id, record_index));
+                    Some(directive) => {
+                        log::debug!("Received directive: {:?}", directive);
+                        stats.directives_buffered.fetch_add(1, Ordering::Relaxed);
+                        directives.insert(directive.file_path.clone(), directive);
                     },
-                    Err(e) => {
-                        log::error!("Error receiving record: {}", e);
-                        // Handle the error
+                    None => {
+                        // no more records will arrive, delete what is left and stop
+                        if !directives.is_empty() {
+                            log::info!("Deleting records after channel closed: {:?}", directives.keys());
+                            stats.flushes_channel_closed.fetch_add(1, Ordering::Relaxed);
+                            flush_records(&mut directives, &client, index, &options, &stats).await?;
+                        }
+                        return Ok(());
                     }
                 }
             }
 
-            // -- mpsc variant --
-            // record = delete_rx.recv() => {
-            //     log::debug!("Received record: {:?}", record);
-            //     if let Some(record) = record {
-            //         let file_path = record
-            //             .get("file_path")
-            //             .and_then(|v| v.as_str())
-            //             .unwrap_or("empty_file_path")
-            //             .to_string();
-
-            //         let record_id = record
-            //             .get("record_id")
-            //             .and_then(|v| v.as_str())
-            //             .unwrap_or("empty_record_id")
-            //             .to_string();
-
-            //         let record_index = record
-            //             .get("record_index")
-            //             .and_then(|v| v.as_str())
-            //             .unwrap_or("empty_record_index")
-            //             .to_string();
-
-            //         file_paths.insert(file_path);
-            //         records.insert((record_id, record_index));
-            //     }
-            // }
-            // -- mpsc variant --
-
             // Timeout after 5 seconds
             _ = sleep(Duration::from_secs(timeout)) => {
                 log::info!("Timeout reached");
-                if !file_paths.is_empty() || !records.is_empty() {
+                if !directives.is_empty() {
 
-                    log::info!("Deleting records after timeout reached: {:?}", file_paths);
+                    log::info!("Deleting records after timeout reached: {:?}", directives.keys());
+                    stats.flushes_timeout.fetch_add(1, Ordering::Relaxed);
 
-                    flush_records(&mut file_paths, &mut records, &es_host, index).await?;
+                    flush_records(&mut directives, &client, index, &options, &stats).await?;
                 }
             }
         }
 
-        if file_paths.len() > buffer_size {
+        stats.progress();
+
+        if directives.len() > buffer_size {
             log::debug!(
                 "Deleting records after buffer size reached: {:?}",
-                file_paths
+                directives.keys()
             );
-            flush_records(&mut file_paths, &mut records, &es_host, index).await?;
+            stats.flushes_buffer_full.fetch_add(1, Ordering::Relaxed);
+            flush_records(&mut directives, &client, index, &options, &stats).await?;
         }
     }
 }
 
 async fn flush_records(
-    file_paths: &mut HashSet<String>,
-    records: &mut HashSet<(String, String)>,
-    es_host: &Host,
+    directives: &mut HashMap<String, DeleteDirective>,
+    client: &SharedClient,
     index: &str,
+    options: &DeleteOptions,
+    stats: &DeleteStats,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let query = generate_query(&*file_paths, &*records)?;
-    log_debug_pretty("Query", &query);
-    let response = delete_records(es_host.clone(), index, query).await?;
-    log_debug_pretty("Response", &response);
-    // clear the file paths and records
-    file_paths.clear();
-    records.clear();
+    let batch: Vec<DeleteDirective> = directives.drain().map(|(_, d)| d).collect();
+
+    if options.dry_run {
+        count_records(batch.iter(), client, index).await?;
+        return Ok(());
+    }
+
+    stats.flushes.fetch_add(1, Ordering::Relaxed);
+
+    let failed = match options.mode {
+        DeleteMode::Query => {
+            let chunks = chunk_directives(&batch, options.max_clauses);
+            if chunks.len() > 1 {
+                log::info!(
+                    "Splitting delete of {} paths into {} queries",
+                    batch.len(),
+                    chunks.len()
+                );
+            }
+            delete_chunks(chunks, client, index, options, stats).await
+        }
+        DeleteMode::Bulk => bulk_delete(&batch, client, index, options, stats).await,
+    };
+
+    requeue(directives, failed, options.max_retries, stats);
+
     Ok(())
 }
 
+// failed paths go back into the buffer for the next flush, until they run out of retries
+// a directive that arrived for the same path in the meantime is newer and wins
+fn requeue(
+    directives: &mut HashMap<String, DeleteDirective>,
+    failed: Vec<&DeleteDirective>,
+    max_retries: u32,
+    stats: &DeleteStats,
+) {
+    for directive in failed {
+        if directive.attempts >= max_retries {
+            log::error!(
+                "Giving up on {} after {} retries",
+                directive.file_path,
+                directive.attempts
+            );
+            stats.paths_dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+
+        if let Entry::Vacant(entry) = directives.entry(directive.file_path.clone()) {
+            entry.insert(DeleteDirective {
+                attempts: directive.attempts + 1,
+                ..directive.clone()
+            });
+            stats.paths_requeued.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn bulk_delete<'a>(
+    batch: &'a [DeleteDirective],
+    client: &SharedClient,
+    index: &str,
+    options: &DeleteOptions,
+    stats: &DeleteStats,
+) -> Vec<&'a DeleteDirective> {
+    let client = client.get();
+
+    match bulk_delete_directives(&client, index, batch, options.bulk_size, stats).await {
+        Ok((bulk_stats, failed)) => {
+            log::info!(
+                "Bulk deleted {} documents for {} paths ({} not found, {} failed)",
+                bulk_stats.deleted,
+                batch.len(),
+                bulk_stats.not_found,
+                bulk_stats.failed
+            );
+            stats
+                .deleted
+                .fetch_add(bulk_stats.deleted, Ordering::Relaxed);
+            stats
+                .failures
+                .fetch_add(bulk_stats.failed, Ordering::Relaxed);
+            failed
+        }
+        Err(e) => {
+            log::error!("Bulk delete failed: {}", e);
+            stats.failures.fetch_add(1, Ordering::Relaxed);
+            batch.iter().collect()
+        }
+    }
+}
+
+// splits the buffer so every delete query stays within the clause budget
+pub fn chunk_directives<'a>(
+    directives: impl IntoIterator<Item = &'a DeleteDirective>,
+    max_clauses: usize,
+) -> Vec<Vec<&'a DeleteDirective>> {
+    let mut chunks = vec![];
+    let mut chunk = vec![];
+    let mut clauses = QUERY_CLAUSES;
+
+    for directive in directives {
+        let cost = clause_cost(directive);
+        if !chunk.is_empty() && clauses + cost > max_clauses {
+            chunks.push(std::mem::take(&mut chunk));
+            clauses = QUERY_CLAUSES;
+        }
+        chunk.push(directive);
+        clauses += cost;
+    }
+
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+// a chunk that elasticsearch still rejects for having too many clauses is retried in halves,
+// returns the directives of every chunk that failed
+async fn delete_chunks<'a>(
+    chunks: Vec<Vec<&'a DeleteDirective>>,
+    client: &SharedClient,
+    index: &str,
+    options: &DeleteOptions,
+    stats: &DeleteStats,
+) -> Vec<&'a DeleteDirective> {
+    let mut pending = chunks;
+    pending.reverse();
+
+    let mut failed = vec![];
+
+    while let Some(chunk) = pending.pop() {
+        let query = generate_query(chunk.iter().copied()).map_err(|e| e.to_string());
+        let response = match query {
+            Ok(query) => {
+                log_debug_pretty("Query", &query);
+                send_delete(client, index, query, &chunk, options, stats)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e),
+        };
+
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Delete query for {} paths failed: {}", chunk.len(), e);
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                failed.extend(chunk);
+                continue;
+            }
+        };
+
+        log::debug!("Response: {:?}", response);
+
+        if response.is_too_many_clauses() {
+            if chunk.len() == 1 {
+                log::error!(
+                    "Delete query for {} exceeds the cluster's max_clause_count",
+                    chunk[0].file_path
+                );
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                failed.extend(chunk);
+                continue;
+            }
+
+            log::warn!(
+                "Delete query for {} paths was rejected for too many clauses, retrying in halves",
+                chunk.len()
+            );
+            let (first, second) = chunk.split_at(chunk.len() / 2);
+            pending.push(second.to_vec());
+            pending.push(first.to_vec());
+            continue;
+        }
+
+        stats.progress();
+        if !record_response(&response, chunk.len(), stats) {
+            failed.extend(chunk);
+        }
+    }
+
+    failed
+}
+
+// the parts of a delete_by_query response the delete task acts on
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteByQueryResponse {
+    #[serde(skip)]
+    pub status: u16,
+    #[serde(default)]
+    pub timed_out: bool,
+    #[serde(default)]
+    pub total: u64,
+    #[serde(default)]
+    pub deleted: u64,
+    #[serde(default)]
+    pub version_conflicts: u64,
+    #[serde(default)]
+    pub failures: Vec<Value>,
+    pub error: Option<Value>,
+    // only set when submitted with wait_for_completion=false
+    pub task: Option<String>,
+}
+
+impl DeleteByQueryResponse {
+    // everything the query matched is gone
+    pub fn is_complete(&self) -> bool {
+        (200..300).contains(&self.status)
+            && self.error.is_none()
+            && !self.timed_out
+            && self.failures.is_empty()
+            && self.version_conflicts == 0
+    }
+
+    pub fn is_too_many_clauses(&self) -> bool {
+        self.error.as_ref().is_some_and(|error| {
+            let error = error.to_string();
+            error.contains("too_many_clauses")
+                || error.contains("too_many_nested_clauses")
+                || error.contains("maxClauseCount")
+        })
+    }
+}
+
+// totals of the delete task since start
+#[derive(Debug, Default)]
+pub struct DeleteStats {
+    pub flushes: AtomicU64,
+    pub deleted: AtomicU64,
+    pub version_conflicts: AtomicU64,
+    // failed delete requests and failed bulk items
+    pub failures: AtomicU64,
+    pub paths_requeued: AtomicU64,
+    pub paths_dropped: AtomicU64,
+    // directives that never made it into the delete queue
+    pub not_enqueued: AtomicU64,
+    pub directives_buffered: AtomicU64,
+    // flushes by what triggered them
+    pub flushes_timeout: AtomicU64,
+    pub flushes_buffer_full: AtomicU64,
+    pub flushes_channel_closed: AtomicU64,
+    // unix seconds, the loop passes at least once per timeout while it is healthy,
+    // long deletes report each task poll and bulk page
+    pub last_progress: AtomicU64,
+}
+
+impl DeleteStats {
+    pub fn progress(&self) {
+        self.last_progress.store(unix_now(), Ordering::Relaxed);
+    }
+
+    pub fn log_summary(&self) {
+        log::info!(
+            "Deleted {} documents in {} flushes ({} version conflicts, {} failures, {} paths requeued, {} paths dropped, {} paths not enqueued)",
+            self.deleted.load(Ordering::Relaxed),
+            self.flushes.load(Ordering::Relaxed),
+            self.version_conflicts.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed),
+            self.paths_requeued.load(Ordering::Relaxed),
+            self.paths_dropped.load(Ordering::Relaxed),
+            self.not_enqueued.load(Ordering::Relaxed)
+        );
+    }
+}
+
+// adds a finished delete to the stats, false when not everything the query matched was deleted
+fn record_response(response: &DeleteByQueryResponse, paths: usize, stats: &DeleteStats) -> bool {
+    stats.deleted.fetch_add(response.deleted, Ordering::Relaxed);
+    stats
+        .version_conflicts
+        .fetch_add(response.version_conflicts, Ordering::Relaxed);
+
+    if !response.is_complete() {
+        log::warn!(
+            "Delete query for {} paths incomplete: status {}, deleted {}, {} version conflicts, {} failures, timed out: {}, error: {}",
+            paths,
+            response.status,
+            response.deleted,
+            response.version_conflicts,
+            response.failures.len(),
+            response.timed_out,
+            response.error.as_ref().unwrap_or(&Value::Null)
+        );
+        stats.failures.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+
+    log::info!("Deleted {} documents for {} paths", response.deleted, paths);
+
+    true
+}
+
+// the delete_by_query result, in async mode once its task has finished
+async fn send_delete(
+    client: &SharedClient,
+    index: &str,
+    query: Value,
+    chunk: &[&DeleteDirective],
+    options: &DeleteOptions,
+    stats: &DeleteStats,
+) -> Result<DeleteByQueryResponse, Box<dyn std::error::Error>> {
+    let client = client.get();
+
+    if !options.async_delete {
+        return delete_by_query(&client, index, query, &options.throttle, true).await;
+    }
+
+    let submitted = delete_by_query(&client, index, query, &options.throttle, false).await?;
+
+    // rejected right away, there is no task to wait for
+    let task_id = match &submitted.task {
+        Some(task_id) => task_id.clone(),
+        None => return Ok(submitted),
+    };
+
+    let store = TaskStore::new(&options.task_file);
+    store.add(PendingTask {
+        task: task_id.clone(),
+        submitted: chrono::Utc::now().to_rfc3339(),
+        directives: chunk.iter().map(|d| (*d).clone()).collect(),
+    })?;
+
+    log::info!(
+        "Submitted delete task {} for {} paths",
+        task_id,
+        chunk.len()
+    );
+
+    // a crash leaves the task in the task file for resume_tasks on the next start, a task this run
+    // lost track of is dropped from it since the caller requeues its paths
+    let response = match wait_for_task(&client, &task_id, stats).await {
+        Ok(response) => response,
+        Err(e) => {
+            let e = e.to_string();
+            store.remove(&task_id)?;
+            return Err(format!("Lost track of delete task {}: {}", task_id, e).into());
+        }
+    };
+    store.remove(&task_id)?;
+
+    Ok(response)
+}
+
+// picks up the tasks an earlier run submitted but did not see finish,
+// their paths are requeued unless the task completed cleanly
+async fn resume_tasks(
+    directives: &mut HashMap<String, DeleteDirective>,
+    client: &SharedClient,
+    options: &DeleteOptions,
+    stats: &DeleteStats,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let store = TaskStore::new(&options.task_file);
+    let tasks = store.load()?;
+    if tasks.is_empty() {
+        return Ok(());
+    }
+
+    let client = client.get();
+
+    for task in tasks {
+        log::info!(
+            "Resuming delete task {} submitted at {} for {} paths",
+            task.task,
+            task.submitted,
+            task.directives.len()
+        );
+
+        let response = wait_for_task(&client, &task.task, stats)
+            .await
+            .map_err(|e| e.to_string());
+
+        let complete = match response {
+            Ok(response) => record_response(&response, task.directives.len(), stats),
+            Err(e) => {
+                log::error!("Lost track of delete task {}: {}", task.task, e);
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        };
+
+        if !complete {
+            requeue(
+                directives,
+                task.directives.iter().collect(),
+                options.max_retries,
+                stats,
+            );
+        }
+
+        store.remove(&task.task)?;
+    }
+
+    Ok(())
+}
+
+// dry run: count what the delete query would remove, path by path, instead of deleting it
+async fn count_records<'a>(
+    directives: impl ExactSizeIterator<Item = &'a DeleteDirective>,
+    client: &SharedClient,
+    index: &str,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let client = client.get();
+
+    let paths = directives.len();
+    let mut total = 0;
+
+    for directive in directives {
+        let query = generate_query([directive])?;
+
+        let count = count_query(&client, index, query).await?;
+
+        log::info!(
+            "Dry run: would delete {} documents for {}",
+            count,
+            directive.file_path
+        );
+        total += count;
+    }
+
+    log::info!(
+        "Dry run: would delete {} documents for {} file paths",
+        total,
+        paths
+    );
+
+    Ok(total)
+}
+
+// a failed or unreadable count is an error, it would otherwise read as nothing to delete
+pub async fn count_query(
+    client: &Elasticsearch,
+    index: &str,
+    query: Value,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let response = client
+        .count(CountParts::Index(&[index]))
+        .body(query)
+        .send()
+        .await?;
+
+    let status = response.status_code().as_u16();
+    let json_response = response.json::<Value>().await?;
+
+    if !(200..300).contains(&status) {
+        return Err(format!("Count failed: status {}: {}", status, json_response).into());
+    }
+
+    match json_response["count"].as_u64() {
+        Some(count) => Ok(count),
+        None => Err(format!("Unexpected count response: {}", json_response).into()),
+    }
+}
+
 fn log_debug_pretty<T: serde::Serialize>(label: &str, value: &T) {
     if log::log_enabled!(log::Level::Debug) {
         if let Ok(value_string) = serde_json::to_string_pretty(value) {
@@ -133,41 +630,56 @@ fn log_debug_pretty<T: serde::Serialize>(label: &str, value: &T) {
     }
 }
 
-fn generate_query(
-    file_paths: &HashSet<String>,
-    records: &HashSet<(String, String)>,
+// paths whose records share a timestamp are matched by one terms clause,
+// cascading paths add a prefix for everything below them
+pub fn generate_query<'a>(
+    directives: impl IntoIterator<Item = &'a DeleteDirective>,
 ) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut by_timestamp: BTreeMap<&str, Vec<&DeleteDirective>> = BTreeMap::new();
+    let mut kept_ids = vec![];
+
+    for directive in directives {
+        by_timestamp
+            .entry(directive.timestamp.as_str())
+            .or_default()
+            .push(directive);
+
+        // removed paths keep nothing
+        if let Some(keep) = &directive.keep {
+            kept_ids.push(keep.id.as_str());
+        }
+    }
+
     let mut file_paths_query = vec![];
-    let mut records_query = vec![];
 
-    for file_path in file_paths {
-        file_paths_query.push(json!({
-            "term": {
-                "file.uri": file_path
+    for (timestamp, group) in by_timestamp {
+        let file_paths: Vec<&str> = group.iter().map(|d| d.file_path.as_str()).collect();
+
+        let mut path_query = vec![json!({
+            "terms": {
+                "file.uri": file_paths
             }
-        }));
+        })];
 
-        file_paths_query.push(json!({
-            "wildcard": {
-                "file.uri": {
-                    "value": format!("{}/*", file_path)
+        for directive in group.iter().filter(|d| d.cascade) {
+            path_query.push(json!({
+                "prefix": {
+                    "file.uri": format!("{}/", directive.file_path)
                 }
-            }
-        }));
-    }
+            }));
+        }
 
-    for (record_id, record_index) in records {
-        records_query.push(json!({
+        // events indexed after the record the directive is based on are never deleted
+        file_paths_query.push(json!({
             "bool": {
-                "must": [
-                    {
-                        "term": {
-                            "_id": record_id
-                        }
-                    },
+                "should": path_query,
+                "minimum_should_match": 1,
+                "filter": [
                     {
-                        "term": {
-                            "_index": record_index
+                        "range": {
+                            "@timestamp": {
+                                "lte": timestamp
+                            }
                         }
                     }
                 ]
@@ -175,6 +687,16 @@ fn generate_query(
         }));
     }
 
+    // kept records are excluded by id, auto generated ids do not repeat across backing indices
+    let mut records_query = vec![];
+    if !kept_ids.is_empty() {
+        records_query.push(json!({
+            "terms": {
+                "_id": kept_ids
+            }
+        }));
+    }
+
     let query = json!({
         "query": {
             "bool": {
@@ -187,20 +709,301 @@ fn generate_query(
     Ok(query)
 }
 
-async fn delete_records(
-    es_host: Host,
+pub async fn delete_records(
+    client: &Elasticsearch,
     index: &str,
     query: Value,
-) -> Result<Value, Box<dyn std::error::Error>> {
-    let client = create_client(es_host.clone())?;
+    throttle: &Throttle,
+) -> Result<DeleteByQueryResponse, Box<dyn std::error::Error>> {
+    delete_by_query(client, index, query, throttle, true).await
+}
 
-    let response = client
-        .delete_by_query(DeleteByQueryParts::Index(&[index]))
-        .body(query)
-        .send()
-        .await?;
+// conflicts are counted instead of aborting the whole query
+async fn delete_by_query(
+    client: &Elasticsearch,
+    index: &str,
+    query: Value,
+    throttle: &Throttle,
+    wait_for_completion: bool,
+) -> Result<DeleteByQueryResponse, Box<dyn std::error::Error>> {
+    let indices = [index];
+    let mut request = client
+        .delete_by_query(DeleteByQueryParts::Index(&indices))
+        .conflicts(Conflicts::Proceed)
+        .slices(throttle.slices.clone())
+        .wait_for_completion(wait_for_completion)
+        .body(query);
 
+    if let Some(requests_per_second) = throttle.requests_per_second {
+        request = request.requests_per_second(requests_per_second.into());
+    }
+
+    let response = request.send().await?;
+
+    let status = response.status_code().as_u16();
     let json_response = response.json::<Value>().await?;
 
-    Ok(json_response)
+    let response = DeleteByQueryResponse {
+        status,
+        ..serde_json::from_value(json_response)?
+    };
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::RecordRef;
+
+    fn directive(file_path: &str, keep: bool, cascade: bool) -> DeleteDirective {
+        DeleteDirective {
+            file_path: file_path.to_string(),
+            event_type: "change".to_string(),
+            keep: keep.then(|| RecordRef {
+                id: "abc".to_string(),
+                index: "index".to_string(),
+            }),
+            cascade,
+            timestamp: "2024-05-01T10:00:00Z".to_string(),
+            attempts: 0,
+        }
+    }
+
+    fn directive_at(file_path: &str, timestamp: &str) -> DeleteDirective {
+        DeleteDirective {
+            timestamp: timestamp.to_string(),
+            ..directive(file_path, true, false)
+        }
+    }
+
+    // every leaf query and every bool counts against max_clause_count
+    fn count_clauses(query: &Value) -> usize {
+        match query {
+            Value::Object(map) => match map.get("bool") {
+                Some(Value::Object(bool_query)) => {
+                    1 + bool_query
+                        .values()
+                        .filter_map(Value::as_array)
+                        .flatten()
+                        .map(count_clauses)
+                        .sum::<usize>()
+                }
+                _ => 1,
+            },
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_generate_query_only_cascades_when_asked() {
+        let query = generate_query([
+            &directive("/srv/touched", true, false),
+            &directive("/srv/removed", false, true),
+        ])
+        .unwrap();
+
+        let should = query["query"]["bool"]["should"].as_array().unwrap();
+        assert_eq!(should.len(), 1);
+        assert_eq!(
+            should[0]["bool"]["should"],
+            json!([
+                {"terms": {"file.uri": ["/srv/touched", "/srv/removed"]}},
+                {"prefix": {"file.uri": "/srv/removed/"}}
+            ])
+        );
+
+        assert_eq!(
+            query["query"]["bool"]["must_not"],
+            json!([{"terms": {"_id": ["abc"]}}])
+        );
+    }
+
+    #[test]
+    fn test_generate_query_bounds_every_path_by_timestamp() {
+        let query = generate_query([
+            &directive_at("/srv/a", "2024-05-01T10:00:00Z"),
+            &directive_at("/srv/b", "2024-05-01T11:00:00Z"),
+            &directive_at("/srv/c", "2024-05-01T10:00:00Z"),
+        ])
+        .unwrap();
+
+        let should = query["query"]["bool"]["should"].as_array().unwrap();
+        assert_eq!(should.len(), 2);
+        assert_eq!(
+            should[0]["bool"]["filter"],
+            json!([{"range": {"@timestamp": {"lte": "2024-05-01T10:00:00Z"}}}])
+        );
+        assert_eq!(
+            should[0]["bool"]["should"],
+            json!([{"terms": {"file.uri": ["/srv/a", "/srv/c"]}}])
+        );
+        assert_eq!(
+            should[1]["bool"]["filter"],
+            json!([{"range": {"@timestamp": {"lte": "2024-05-01T11:00:00Z"}}}])
+        );
+    }
+
+    #[test]
+    fn test_chunks_stay_within_clause_budget() {
+        let directives: Vec<DeleteDirective> = (0..500)
+            .map(|i| DeleteDirective {
+                timestamp: format!("2024-05-01T10:00:{:02}Z", i % 60),
+                ..directive(&format!("/srv/{}", i), i % 3 != 0, i % 3 == 0)
+            })
+            .collect();
+
+        let chunks = chunk_directives(&directives, 64);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.iter().map(Vec::len).sum::<usize>(), 500);
+
+        for chunk in chunks {
+            let query = generate_query(chunk).unwrap();
+            assert!(count_clauses(&query["query"]) <= 64);
+        }
+    }
+
+    #[test]
+    fn test_too_many_clauses_is_detected() {
+        let rejected: DeleteByQueryResponse = serde_json::from_value(json!({
+            "error": {
+                "root_cause": [{"type": "too_many_nested_clauses", "reason": "Query contains too many nested clauses; maxClauseCount is set to 1024"}],
+                "type": "search_phase_execution_exception"
+            },
+            "status": 400
+        }))
+        .unwrap();
+
+        assert!(rejected.is_too_many_clauses());
+        assert!(!rejected.is_complete());
+    }
+
+    #[test]
+    fn test_response_with_conflicts_or_failures_is_incomplete() {
+        let response = |body: Value| DeleteByQueryResponse {
+            status: 200,
+            ..serde_json::from_value(body).unwrap()
+        };
+
+        assert!(
+            response(json!({"deleted": 3, "version_conflicts": 0, "failures": []})).is_complete()
+        );
+        assert!(
+            !response(json!({"deleted": 2, "version_conflicts": 1, "failures": []})).is_complete()
+        );
+        assert!(!response(json!({"deleted": 0, "timed_out": true})).is_complete());
+        assert!(
+            !response(json!({"deleted": 1, "failures": [{"id": "abc", "status": 500}]}))
+                .is_complete()
+        );
+    }
+
+    #[test]
+    fn test_parse_slices() {
+        assert_eq!(parse_slices("auto"), Ok(Slices::Auto));
+        assert_eq!(parse_slices("4"), Ok(Slices::Count(4)));
+        assert!(parse_slices("0").is_err());
+        assert!(parse_slices("many").is_err());
+    }
+
+    #[test]
+    fn test_requeue_caps_retries_and_keeps_newer_directives() {
+        let stats = DeleteStats::default();
+        let mut buffer = HashMap::new();
+
+        let retried = directive("/srv/a", true, false);
+        let exhausted = DeleteDirective {
+            attempts: 3,
+            ..directive("/srv/b", true, false)
+        };
+        let superseded = directive("/srv/c", true, false);
+        let newer = DeleteDirective {
+            timestamp: "2024-05-02T10:00:00Z".to_string(),
+            ..directive("/srv/c", true, false)
+        };
+        buffer.insert(newer.file_path.clone(), newer.clone());
+
+        requeue(
+            &mut buffer,
+            vec![&retried, &exhausted, &superseded],
+            3,
+            &stats,
+        );
+
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer["/srv/a"].attempts, 1);
+        assert_eq!(buffer["/srv/c"], newer);
+        assert_eq!(stats.paths_requeued.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.paths_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lost_task_is_requeued_not_resumed() {
+        use crate::config::{AuthMode, Config};
+        use crate::elastic::{Host, HostConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        // accepts the delete as a task, then no longer knows the task
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = vec![0; 65536];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+                    let (status, body) =
+                        if String::from_utf8_lossy(&request[..read]).contains("_delete_by_query") {
+                            ("200 OK", r#"{"task":"node-1:42"}"#)
+                        } else {
+                            ("404 Not Found", r#"{"error":{"reason":"task not found"}}"#)
+                        };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nX-Elastic-Product: Elasticsearch\r\n\
+                         content-type: application/json\r\ncontent-length: {}\r\n\
+                         connection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        let mut config = Config::default();
+        config.elasticsearch.auth = Some(AuthMode::None);
+        config.elasticsearch.nodes = vec![url];
+        let client = SharedClient::new(Host::new(HostConfig::from(&config.elasticsearch))).unwrap();
+
+        let task_file = std::env::temp_dir().join(format!("condense_tasks_{}", std::process::id()));
+        let options = DeleteOptions {
+            buffer_size: 10,
+            timeout: 5,
+            dry_run: false,
+            mode: DeleteMode::Query,
+            bulk_size: 100,
+            max_clauses: 100,
+            max_retries: 3,
+            throttle: Throttle::default(),
+            async_delete: true,
+            task_file: task_file.display().to_string(),
+        };
+        let stats = DeleteStats::default();
+        let mut buffer = HashMap::new();
+        let lost = directive("/srv/a", false, false);
+        buffer.insert(lost.file_path.clone(), lost);
+
+        flush_records(&mut buffer, &client, "index", &options, &stats)
+            .await
+            .unwrap();
+
+        // retried by this run, so the next start must not resubmit it as well
+        assert_eq!(buffer["/srv/a"].attempts, 1);
+        assert!(TaskStore::new(&options.task_file)
+            .load()
+            .unwrap()
+            .is_empty());
+    }
 }
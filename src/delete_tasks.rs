@@ -0,0 +1,167 @@
+use elasticsearch::tasks::TasksGetParts;
+use elasticsearch::Elasticsearch;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use tokio::time::{sleep, Duration};
+
+use crate::delete_records::{DeleteByQueryResponse, DeleteStats};
+use crate::message::DeleteDirective;
+
+// delete_by_query submitted with wait_for_completion=false runs as a task on the cluster,
+// outstanding tasks are written to the task file so a restart can pick them up again
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingTask {
+    pub task: String,
+    pub submitted: String,
+    // requeued when the task does not complete cleanly
+    pub directives: Vec<DeleteDirective>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskStore {
+    path: PathBuf,
+}
+
+impl TaskStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn load(&self) -> Result<Vec<PendingTask>, Box<dyn std::error::Error>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(
+                    format!("Failed to read task file {}: {}", self.path.display(), e).into(),
+                )
+            }
+        };
+
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid task file {}: {}", self.path.display(), e).into())
+    }
+
+    pub fn add(&self, task: PendingTask) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tasks = self.load()?;
+        tasks.push(task);
+        self.save(&tasks)
+    }
+
+    pub fn remove(&self, task_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tasks = self.load()?;
+        tasks.retain(|task| task.task != task_id);
+        self.save(&tasks)
+    }
+
+    // written to a temporary file first, a crash never leaves a half written task file
+    fn save(&self, tasks: &[PendingTask]) -> Result<(), Box<dyn std::error::Error>> {
+        if tasks.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(tasks)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+// polls the tasks api until the delete finished, logging its progress
+// the result has the same shape as a synchronous delete_by_query response
+pub async fn wait_for_task(
+    client: &Elasticsearch,
+    task_id: &str,
+    stats: &DeleteStats,
+) -> Result<DeleteByQueryResponse, Box<dyn std::error::Error>> {
+    loop {
+        let response = client
+            .tasks()
+            .get(TasksGetParts::TaskId(task_id))
+            .send()
+            .await?;
+
+        let status = response.status_code().as_u16();
+        let body = response.json::<Value>().await?;
+
+        if !(200..300).contains(&status) {
+            return Err(format!(
+                "Failed to get task {}: status {}: {}",
+                task_id, status, body
+            )
+            .into());
+        }
+
+        // a task that runs for hours is still progress as long as it answers
+        stats.progress();
+
+        if body["completed"].as_bool().unwrap_or(false) {
+            return task_result(&body);
+        }
+
+        let progress = &body["task"]["status"];
+        log::info!(
+            "Delete task {}: {} of {} documents deleted, {} version conflicts, {} batches",
+            task_id,
+            progress["deleted"],
+            progress["total"],
+            progress["version_conflicts"],
+            progress["batches"]
+        );
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+// a completed task either has the delete_by_query response or the error it failed with
+fn task_result(body: &Value) -> Result<DeleteByQueryResponse, Box<dyn std::error::Error>> {
+    if let Some(error) = body.get("error") {
+        return Ok(DeleteByQueryResponse {
+            status: 500,
+            error: Some(error.clone()),
+            ..Default::default()
+        });
+    }
+
+    let response = DeleteByQueryResponse {
+        status: 200,
+        ..serde_json::from_value(body["response"].clone())?
+    };
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_task_result_reads_response_or_error() {
+        let done = task_result(&json!({
+            "completed": true,
+            "task": {"status": {"total": 4, "deleted": 3}},
+            "response": {"total": 4, "deleted": 3, "version_conflicts": 1, "failures": [], "timed_out": false}
+        }))
+        .unwrap();
+        assert_eq!(done.deleted, 3);
+        assert_eq!(done.version_conflicts, 1);
+        assert!(!done.is_complete());
+
+        let failed = task_result(&json!({
+            "completed": true,
+            "error": {"type": "too_many_nested_clauses", "reason": "maxClauseCount is set to 1024"}
+        }))
+        .unwrap();
+        assert!(failed.is_too_many_clauses());
+    }
+}
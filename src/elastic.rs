@@ -1,50 +1,128 @@
-use std::io::Read;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::time::{sleep, timeout, Duration};
 // use color_eyre::config;
-use color_eyre::{eyre::Context, Report};
+use color_eyre::{eyre::eyre, eyre::Context, Report};
 use url::Url;
 
-use elasticsearch::{http::transport::Transport, http::transport::TransportBuilder, Elasticsearch};
+use crate::config::{AuthMode, ElasticConfig};
+use crate::supervisor::unix_now;
+
+use elasticsearch::auth::{ClientCertificate, Credentials};
+use elasticsearch::cert::{Certificate, CertificateValidation};
+use elasticsearch::http::headers::{HeaderValue, AUTHORIZATION};
+use elasticsearch::http::response::Response;
+use elasticsearch::http::transport::{
+    CloudConnectionPool, Connection, ConnectionPool, SingleNodeConnectionPool, Transport,
+    TransportBuilder,
+};
+use elasticsearch::nodes::NodesInfoParts;
+use elasticsearch::Elasticsearch;
 // use std::error::Error;
 
+// how often every node of a multi node pool is pinged, and how long a ping may take
+const NODE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const NODE_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// a node that did not answer is skipped this long unless a check finds it back earlier
+const DEAD_NODE_TIMEOUT: u64 = 60;
+
 pub struct HostConfig {
+    // resolved by ElasticConfig::auth_mode, no credentials at all is an error
+    pub auth: Option<AuthMode>,
     pub user: Option<String>,
     pub password: Option<String>,
+    pub api_key_id: Option<String>,
+    pub api_key: Option<String>,
+    pub bearer_token: Option<String>,
     pub host_ip: Option<String>,
     pub host_port: Option<u16>,
     pub host_scheme: Option<String>,
     pub cert_path: Option<String>,
-    // pub verify_certs: Option<bool>,
-    // pub ca_certs: Option<String>,
-    // pub ssl_show_warn: Option<bool>,
+    // full node urls, used instead of host_ip / host_port when set
+    pub nodes: Vec<String>,
+    pub cloud_id: Option<String>,
+    // seconds between node sniffs, no sniffing without
+    pub sniff_interval: Option<u64>,
+    // skips certificate verification entirely, has to be chosen explicitly
+    pub insecure: bool,
+    // certificate (chain) and private key presented to the cluster for mutual TLS
+    pub client_cert_path: Option<String>,
+    pub client_key_path: Option<String>,
+}
+
+impl From<&ElasticConfig> for HostConfig {
+    fn from(config: &ElasticConfig) -> Self {
+        Self {
+            auth: config.auth_mode().ok(),
+            user: config.user.clone(),
+            password: config
+                .password
+                .as_ref()
+                .map(|secret| secret.expose().to_string()),
+            api_key_id: config.api_key_id.clone(),
+            api_key: config
+                .api_key
+                .as_ref()
+                .map(|secret| secret.expose().to_string()),
+            bearer_token: config
+                .bearer_token
+                .as_ref()
+                .map(|secret| secret.expose().to_string()),
+            host_ip: config.host.clone(),
+            host_port: config.port,
+            host_scheme: Some(config.scheme.clone()),
+            cert_path: config.cert_path.clone(),
+            nodes: config.nodes.clone(),
+            cloud_id: config.cloud_id.clone(),
+            sniff_interval: config.sniff.then_some(config.sniff_interval),
+            insecure: config.insecure,
+            client_cert_path: config.client_cert_path.clone(),
+            client_key_path: config.client_key_path.clone(),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Host {
-    user: String,
-    password: String,
+    auth: Option<AuthMode>,
+    user: Option<String>,
+    password: Option<String>,
+    api_key_id: Option<String>,
+    api_key: Option<String>,
+    bearer_token: Option<String>,
     host_ip: String,
     host_port: u16,
     host_scheme: String,
     cert_path: String,
-    // verify_certs: bool,
-    // ca_certs: Option<String>,
-    // ssl_show_warn: bool,
+    nodes: Vec<String>,
+    cloud_id: Option<String>,
+    sniff_interval: Option<u64>,
+    insecure: bool,
+    client_cert_path: String,
+    client_key_path: String,
 }
 
 impl Host {
     pub fn new(config: HostConfig) -> Self {
         Self {
-            user: config.user.unwrap_or_else(|| "default_user".to_string()),
-            password: config
-                .password
-                .unwrap_or_else(|| "default_password".to_string()),
+            auth: config.auth,
+            user: config.user,
+            password: config.password,
+            api_key_id: config.api_key_id,
+            api_key: config.api_key,
+            bearer_token: config.bearer_token,
             host_ip: config.host_ip.unwrap_or_else(|| "localhost".to_string()),
             host_port: config.host_port.unwrap_or(9200),
             host_scheme: config.host_scheme.unwrap_or_else(|| "http".to_string()),
             cert_path: config.cert_path.unwrap_or_default(),
-            // verify_certs: config.verify_certs.unwrap_or(false),
-            // ca_certs: config.ca_certs,
-            // ssl_show_warn: config.ssl_show_warn.unwrap_or(false),
+            nodes: config.nodes,
+            cloud_id: config.cloud_id,
+            sniff_interval: config.sniff_interval,
+            insecure: config.insecure,
+            client_cert_path: config.client_cert_path.unwrap_or_default(),
+            client_key_path: config.client_key_path.unwrap_or_default(),
         }
     }
     // pub fn new(
@@ -76,53 +154,600 @@ impl Host {
         let url = Url::parse(&url_str).wrap_err("Failed to parse URL")?;
         Ok(url)
     }
+
+    // the configured node list, or the single node from host / port / scheme
+    pub fn urls(&self) -> Result<Vec<Url>, Report> {
+        if self.nodes.is_empty() {
+            return Ok(vec![self.url()?]);
+        }
+        self.nodes
+            .iter()
+            .map(|node| {
+                Url::parse(node).wrap_err_with(|| format!("Failed to parse node URL {}", node))
+            })
+            .collect()
+    }
+
+    // the scheme of the configured nodes, validate makes sure they share one when sniffing
+    fn sniff_scheme(&self) -> Result<String, Report> {
+        Ok(self.urls()?[0].scheme().to_string())
+    }
+
+    // what the client connects to, for logs and check-config
+    pub fn describe(&self) -> Result<String, Report> {
+        if let Some(cloud_id) = &self.cloud_id {
+            // the part before the colon is only a label
+            let label = cloud_id.split(':').next().unwrap_or_default();
+            return Ok(format!("cloud {}", label));
+        }
+        let urls: Vec<String> = self.urls()?.iter().map(Url::to_string).collect();
+        Ok(urls.join(", "))
+    }
+}
+
+// method, url and status of a response for the debug log, the Debug output of a
+// response includes its headers and those may carry cookies or auth challenges
+pub fn describe_response(response: &Response) -> String {
+    let mut url = response.url().clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    format!("{:?} {} {}", response.method(), url, response.status_code())
+}
+
+// which nodes of a pool are skipped, the transport does not retry a failed request on
+// another node so the pool has to know beforehand. set by SharedClient::check_nodes
+#[derive(Debug, Default)]
+pub struct NodeHealth {
+    // unix seconds until which the node is skipped, per node
+    dead_until: Vec<AtomicU64>,
+}
+
+impl NodeHealth {
+    pub fn new(nodes: usize) -> Self {
+        Self {
+            dead_until: (0..nodes).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn is_alive(&self, index: usize, now: u64) -> bool {
+        self.dead_until[index].load(Ordering::Relaxed) <= now
+    }
+
+    // true if the node was alive until now
+    fn mark_dead(&self, index: usize, now: u64) -> bool {
+        let previous = self.dead_until[index].swap(now + DEAD_NODE_TIMEOUT, Ordering::Relaxed);
+        previous <= now
+    }
+
+    // true if the node was dead until now
+    fn mark_alive(&self, index: usize, now: u64) -> bool {
+        let previous = self.dead_until[index].swap(0, Ordering::Relaxed);
+        previous > now
+    }
+}
+
+// round robin over a list of nodes, skipping the ones the last check found dead.
+// when every node is dead the requests go round robin over all of them.
+// sniffing replaces the list with the nodes the cluster reports
+#[derive(Debug, Clone)]
+pub struct MultiNodeConnectionPool {
+    connections: Vec<Connection>,
+    next: Arc<AtomicUsize>,
+    health: Arc<NodeHealth>,
+}
+
+impl MultiNodeConnectionPool {
+    pub fn new(urls: Vec<Url>, health: Arc<NodeHealth>) -> Self {
+        Self {
+            connections: urls.into_iter().map(Connection::new).collect(),
+            next: Arc::new(AtomicUsize::new(0)),
+            health,
+        }
+    }
 }
 
-fn create_transport(es_host: Host) -> Result<Transport, Report> {
-    let connection_pool =
-        elasticsearch::http::transport::SingleNodeConnectionPool::new(es_host.url()?);
-    let credentials = elasticsearch::auth::Credentials::Basic(
-        es_host.user.to_string(),
-        es_host.password.to_string(),
-    );
-    let cert = get_certificate_validation(&es_host.cert_path)?;
+impl ConnectionPool for MultiNodeConnectionPool {
+    fn next(&self) -> &Connection {
+        let count = self.connections.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let now = unix_now();
+        let index = (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|index| self.health.is_alive(*index, now))
+            .unwrap_or(start % count);
+        &self.connections[index]
+    }
+}
+
+fn create_transport(
+    es_host: &Host,
+    urls: Vec<Url>,
+    health: Arc<NodeHealth>,
+) -> Result<Transport, Report> {
+    let builder = match &es_host.cloud_id {
+        Some(cloud_id) => TransportBuilder::new(CloudConnectionPool::new(cloud_id)?),
+        None if urls.len() == 1 => {
+            TransportBuilder::new(SingleNodeConnectionPool::new(urls[0].clone()))
+        }
+        None => TransportBuilder::new(MultiNodeConnectionPool::new(urls, health)),
+    };
+    let mut builder = builder.cert_validation(get_certificate_validation(es_host)?);
+
+    // the authorization header is set directly, the credentials slot of the transport
+    // is taken by the client certificate when mutual TLS is used
+    if let Some(value) = auth_header(es_host)? {
+        builder = builder.header(AUTHORIZATION, value);
+    }
+    if let Some(identity) = client_identity(es_host)? {
+        builder = builder.auth(Credentials::Certificate(ClientCertificate::Pem(identity)));
+    }
 
-    let transport = TransportBuilder::new(connection_pool)
-        .auth(credentials)
-        .cert_validation(cert)
-        .build()?;
+    let transport = builder.build()?;
     Ok(transport)
 }
 
-fn get_certificate_validation(
-    cert_path: &str,
-) -> Result<elasticsearch::cert::CertificateValidation, Report> {
-    // check if the cert_path is empty, if it is, return None, otherwise read the cert file and return the Certificate
-    match cert_path.is_empty() {
-        true => Ok(elasticsearch::cert::CertificateValidation::None),
-        false => {
-            let mut buf = Vec::new();
-            std::fs::File::open(cert_path)?.read_to_end(&mut buf)?;
-            let raw_cert = elasticsearch::cert::Certificate::from_pem(&buf)?;
-            Ok(elasticsearch::cert::CertificateValidation::Certificate(
-                raw_cert,
-            ))
+fn auth_header(es_host: &Host) -> Result<Option<HeaderValue>, Report> {
+    let value = match es_host.auth {
+        Some(AuthMode::Basic) => {
+            let user = required(&es_host.user, "user")?;
+            let password = required(&es_host.password, "password")?;
+            format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", user, password))
+            )
+        }
+        Some(AuthMode::ApiKey) => {
+            let api_key = required(&es_host.api_key, "API key")?;
+            match &es_host.api_key_id {
+                Some(id) => format!("ApiKey {}", STANDARD.encode(format!("{}:{}", id, api_key))),
+                // already base64 encoded "id:key", as elasticsearch returns it in "encoded"
+                None => format!("ApiKey {}", api_key),
+            }
+        }
+        Some(AuthMode::Bearer) => {
+            format!(
+                "Bearer {}",
+                required(&es_host.bearer_token, "bearer token")?
+            )
         }
+        Some(AuthMode::None) => {
+            log::warn!("Elasticsearch authentication is disabled");
+            return Ok(None);
+        }
+        None => return Err(eyre!("No Elasticsearch credentials configured")),
+    };
+
+    let mut value = HeaderValue::from_str(&value).wrap_err("Invalid Elasticsearch credentials")?;
+    value.set_sensitive(true);
+
+    Ok(Some(value))
+}
+
+fn required(value: &Option<String>, name: &str) -> Result<String, Report> {
+    value
+        .clone()
+        .ok_or_else(|| eyre!("No Elasticsearch {} configured", name))
+}
+
+// CertificateValidation::Default finds the system trust store only because Cargo.toml turns on
+// rustls-tls-native-roots for reqwest-elasticsearch, which works as long as that is the reqwest
+// elasticsearch itself is built on. this stops compiling once the two versions drift apart
+const _: fn(Certificate) -> Vec<reqwest_elasticsearch::Certificate> =
+    |chain| chain.into_iter().collect();
+
+// verified against the system trust store unless a CA bundle is given,
+// every certificate in the bundle is trusted so a full chain works as well as a single CA
+fn get_certificate_validation(es_host: &Host) -> Result<CertificateValidation, Report> {
+    // warned about once at start in main, not for every transport built here
+    if es_host.insecure {
+        return Ok(CertificateValidation::None);
+    }
+
+    if es_host.cert_path.is_empty() {
+        return Ok(CertificateValidation::Default);
     }
+
+    let bundle = read_pem(&es_host.cert_path)?;
+    let chain = Certificate::from_pem(&bundle)
+        .wrap_err_with(|| format!("Invalid certificate bundle {}", es_host.cert_path))?;
+
+    Ok(CertificateValidation::Full(chain))
+}
+
+// certificate chain and private key in one PEM blob, the way reqwest reads an identity
+fn client_identity(es_host: &Host) -> Result<Option<Vec<u8>>, Report> {
+    if es_host.client_cert_path.is_empty() {
+        return Ok(None);
+    }
+
+    let mut identity = read_pem(&es_host.client_cert_path)?;
+    identity.push(b'\n');
+    identity.extend(read_pem(&es_host.client_key_path)?);
+
+    Ok(Some(identity))
+}
+
+// windows line endings would hide the BEGIN / END markers from the pem parser
+fn read_pem(path: &str) -> Result<Vec<u8>, Report> {
+    let pem = std::fs::read_to_string(path).wrap_err_with(|| format!("Failed to read {}", path))?;
+    Ok(pem.replace("\r\n", "\n").into_bytes())
 }
 
 pub fn create_client(es_host: Host) -> Result<Elasticsearch, Report> {
-    let transport = create_transport(es_host)?;
+    let urls = es_host.urls()?;
+    let health = Arc::new(NodeHealth::new(urls.len()));
+    let transport = create_transport(&es_host, urls, health)?;
     let client = Elasticsearch::new(transport);
     Ok(client)
 }
 
+// the client over all nodes and, when there are several, one client per node for check_nodes
+fn create_clients(
+    es_host: &Host,
+    nodes: &[Url],
+    health: &Arc<NodeHealth>,
+) -> Result<(Elasticsearch, Vec<Elasticsearch>), Report> {
+    let client = Elasticsearch::new(create_transport(es_host, nodes.to_vec(), health.clone())?);
+    if es_host.cloud_id.is_some() || nodes.len() < 2 {
+        return Ok((client, vec![]));
+    }
+
+    let node_clients = nodes
+        .iter()
+        .map(|node| {
+            let transport = create_transport(es_host, vec![node.clone()], health.clone())?;
+            Ok(Elasticsearch::new(transport))
+        })
+        .collect::<Result<_, Report>>()?;
+    Ok((client, node_clients))
+}
+
+// one client and connection pool for the whole process, cloning the handle is cheap
+// the client is only rebuilt by reload_if_changed, when the certificate on disk changed
+#[derive(Clone)]
+pub struct SharedClient {
+    es_host: Host,
+    state: Arc<RwLock<ClientState>>,
+}
+
+struct ClientState {
+    client: Elasticsearch,
+    // of the CA bundle, client certificate and key
+    cert_modified: Vec<Option<SystemTime>>,
+    // the configured nodes until sniffing found others
+    nodes: Vec<Url>,
+    health: Arc<NodeHealth>,
+    // one per node, in the order of nodes, empty with a single node
+    node_clients: Vec<Elasticsearch>,
+}
+
+impl SharedClient {
+    pub fn new(es_host: Host) -> Result<Self, Report> {
+        let cert_modified = cert_modified(&es_host);
+        let nodes = es_host.urls()?;
+        let health = Arc::new(NodeHealth::new(nodes.len()));
+        let (client, node_clients) = create_clients(&es_host, &nodes, &health)?;
+
+        Ok(Self {
+            es_host,
+            state: Arc::new(RwLock::new(ClientState {
+                client,
+                cert_modified,
+                nodes,
+                health,
+                node_clients,
+            })),
+        })
+    }
+
+    // the client shares its transport with every other clone
+    pub fn get(&self) -> Elasticsearch {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        state.client.clone()
+    }
+
+    // true if the certificate changed and the client was rebuilt with it,
+    // a certificate that fails to load keeps the current client
+    pub fn reload_if_changed(&self) -> Result<bool, Report> {
+        let modified = cert_modified(&self.es_host);
+        let (nodes, health) = {
+            let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+            if modified == state.cert_modified {
+                return Ok(false);
+            }
+            (state.nodes.clone(), state.health.clone())
+        };
+
+        // same nodes, so what is known about their health still holds
+        let (client, node_clients) = create_clients(&self.es_host, &nodes, &health)
+            .wrap_err("Failed to reload certificates")?;
+
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        state.client = client;
+        state.node_clients = node_clients;
+        state.cert_modified = modified;
+
+        Ok(true)
+    }
+
+    // asks the cluster for its http nodes and rebuilds the client when they differ from the current ones,
+    // true if the node list changed
+    pub async fn sniff(&self) -> Result<bool, Report> {
+        let response = self
+            .get()
+            .nodes()
+            .info(NodesInfoParts::Metric(&["http"]))
+            .send()
+            .await?
+            .error_for_status_code()?;
+
+        let body = response.json::<serde_json::Value>().await?;
+
+        let mut nodes = sniffed_nodes(&body, &self.es_host.sniff_scheme()?)?;
+        if nodes.is_empty() {
+            return Err(eyre!("Node sniffing returned no http nodes"));
+        }
+        nodes.sort();
+
+        if nodes == self.state.read().unwrap_or_else(|e| e.into_inner()).nodes {
+            return Ok(false);
+        }
+
+        let health = Arc::new(NodeHealth::new(nodes.len()));
+        let (client, node_clients) = create_clients(&self.es_host, &nodes, &health)?;
+
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        state.client = client;
+        state.nodes = nodes;
+        state.health = health;
+        state.node_clients = node_clients;
+
+        Ok(true)
+    }
+
+    // pings every node of a multi node pool, a node that does not answer is skipped by the pool
+    // until it answers again. any status counts as an answer, only the connection matters here
+    pub async fn check_nodes(&self) {
+        let (nodes, health, node_clients) = {
+            let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+            (
+                state.nodes.clone(),
+                state.health.clone(),
+                state.node_clients.clone(),
+            )
+        };
+
+        for (index, (node, client)) in nodes.iter().zip(node_clients).enumerate() {
+            let error = match timeout(NODE_CHECK_TIMEOUT, client.ping().send()).await {
+                Ok(Ok(_)) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(format!(
+                    "no answer within {} seconds",
+                    NODE_CHECK_TIMEOUT.as_secs()
+                )),
+            };
+            let now = unix_now();
+            match error {
+                Some(e) => {
+                    if health.mark_dead(index, now) {
+                        log::warn!("Node {} is unreachable, skipping it: {}", node, e);
+                    }
+                }
+                None => {
+                    if health.mark_alive(index, now) {
+                        log::info!("Node {} is reachable again", node);
+                    }
+                }
+            }
+        }
+    }
+
+    fn nodes(&self) -> Vec<Url> {
+        self.state
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .nodes
+            .clone()
+    }
+}
+
+// publish_address is either "ip:port" or "hostname/ip:port"
+fn sniffed_nodes(body: &serde_json::Value, scheme: &str) -> Result<Vec<Url>, Report> {
+    let Some(nodes) = body["nodes"].as_object() else {
+        return Err(eyre!("Unexpected nodes info response: {}", body));
+    };
+
+    nodes
+        .values()
+        .filter_map(|node| node["http"]["publish_address"].as_str())
+        .map(|address| {
+            let address = address.rsplit('/').next().unwrap_or(address);
+            Url::parse(&format!("{}://{}", scheme, address))
+                .wrap_err_with(|| format!("Invalid publish address {}", address))
+        })
+        .collect()
+}
+
+// sniffs the cluster nodes every sniff interval, a failed sniff keeps the current nodes
+pub async fn watch_nodes(client: SharedClient) {
+    let Some(interval) = client.es_host.sniff_interval else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+
+        match client.sniff().await {
+            Ok(true) => {
+                let nodes = client.nodes();
+                let nodes: Vec<&str> = nodes.iter().map(Url::as_str).collect();
+                log::info!("Sniffed new node list: {}", nodes.join(", "))
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!("Failed to sniff nodes: {:#}", e),
+        }
+    }
+}
+
+// checks the nodes every NODE_CHECK_INTERVAL, nothing to do for a single node or a cloud deployment
+// but sniffing may turn one node into several, so the loop keeps running
+pub async fn watch_node_health(client: SharedClient) {
+    loop {
+        client.check_nodes().await;
+        sleep(NODE_CHECK_INTERVAL).await;
+    }
+}
+
+// checks the certificates every interval and swaps in a rebuilt client when one changed,
+// without certificate files there is nothing to watch
+pub async fn watch_certificate(client: SharedClient, interval: Duration) {
+    if client.es_host.cert_path.is_empty() && client.es_host.client_cert_path.is_empty() {
+        return std::future::pending().await;
+    }
+
+    loop {
+        sleep(interval).await;
+
+        match client.reload_if_changed() {
+            Ok(true) => log::info!("Certificates changed, client reloaded"),
+            Ok(false) => {}
+            Err(e) => log::warn!("{:#}", e),
+        }
+    }
+}
+
+fn cert_modified(es_host: &Host) -> Vec<Option<SystemTime>> {
+    [
+        &es_host.cert_path,
+        &es_host.client_cert_path,
+        &es_host.client_key_path,
+    ]
+    .iter()
+    .map(|path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+    .collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use color_eyre::config;
-
     use super::*;
 
+    #[test]
+    fn test_sniffed_nodes_use_publish_address() {
+        let body = serde_json::json!({
+            "nodes": {
+                "a": {"http": {"publish_address": "10.0.0.1:9200"}},
+                "b": {"http": {"publish_address": "es-2.local/10.0.0.2:9200"}},
+                "c": {"roles": ["master"]}
+            }
+        });
+
+        let mut nodes = sniffed_nodes(&body, "https").unwrap();
+        nodes.sort();
+
+        assert_eq!(
+            nodes,
+            vec![
+                Url::parse("https://10.0.0.1:9200").unwrap(),
+                Url::parse("https://10.0.0.2:9200").unwrap()
+            ]
+        );
+    }
+
+    fn next_node(pool: &MultiNodeConnectionPool) -> usize {
+        let connection = pool.next();
+        pool.connections
+            .iter()
+            .position(|candidate| std::ptr::eq(candidate, connection))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pool_skips_dead_nodes() {
+        let urls: Vec<Url> = ["http://10.0.0.1:9200", "http://10.0.0.2:9200"]
+            .iter()
+            .map(|url| Url::parse(url).unwrap())
+            .collect();
+        let health = Arc::new(NodeHealth::new(urls.len()));
+        let pool = MultiNodeConnectionPool::new(urls, health.clone());
+        let now = unix_now();
+
+        assert!(health.mark_dead(0, now));
+        for _ in 0..4 {
+            assert_eq!(next_node(&pool), 1);
+        }
+
+        // with every node dead the pool still hands them out in turn
+        assert!(health.mark_dead(1, now));
+        assert_ne!(next_node(&pool), next_node(&pool));
+
+        assert!(health.mark_alive(0, now));
+        assert!(!health.mark_alive(0, now));
+        assert_eq!(next_node(&pool), 0);
+    }
+
+    #[tokio::test]
+    async fn test_requests_avoid_unreachable_node() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        // answers every request with an empty 200
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let _ = stream.read(&mut request).await;
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nX-Elastic-Product: Elasticsearch\r\n\
+                              content-length: 0\r\nconnection: close\r\n\r\n",
+                        )
+                        .await;
+                });
+            }
+        });
+
+        let config = HostConfig {
+            auth: Some(AuthMode::None),
+            user: None,
+            password: None,
+            api_key_id: None,
+            api_key: None,
+            bearer_token: None,
+            host_ip: None,
+            host_port: None,
+            // the nodes say http, sniffing must not switch to https
+            host_scheme: Some("https".to_string()),
+            cert_path: None,
+            // nothing listens on port 1
+            nodes: vec![
+                "http://127.0.0.1:1".to_string(),
+                format!("http://127.0.0.1:{}", port),
+            ],
+            cloud_id: None,
+            sniff_interval: None,
+            insecure: false,
+            client_cert_path: None,
+            client_key_path: None,
+        };
+        let host = Host::new(config);
+        assert_eq!(host.sniff_scheme().unwrap(), "http");
+        let client = SharedClient::new(host).unwrap();
+
+        client.check_nodes().await;
+
+        for _ in 0..4 {
+            let response = client.get().ping().send().await.expect("ping failed");
+            assert_eq!(response.status_code(), 200);
+        }
+    }
+
     #[tokio::test]
     async fn test_create_client_with_cert() {
         use dotenv::dotenv;
@@ -139,15 +764,22 @@ mod tests {
         let es_password = env::var("ES_PASSWORD").ok();
 
         let config = HostConfig {
+            auth: Some(AuthMode::Basic),
             user: es_user,
             password: es_password,
+            api_key_id: None,
+            api_key: None,
+            bearer_token: None,
             host_ip: es_ip,
             host_port: es_port.map(|p| p.parse::<u16>().unwrap()),
             host_scheme: Some("https".to_string()),
             cert_path,
-            // verify_certs: Some(true),
-            // ca_certs: None,
-            // ssl_show_warn: Some(true),
+            nodes: vec![],
+            cloud_id: None,
+            sniff_interval: None,
+            insecure: false,
+            client_cert_path: None,
+            client_key_path: None,
         };
 
         let es_host = Host::new(config);
@@ -191,15 +823,22 @@ mod tests {
         let es_password = env::var("ES_PASSWORD").ok();
 
         let config = HostConfig {
+            auth: Some(AuthMode::Basic),
             user: es_user,
             password: es_password,
+            api_key_id: None,
+            api_key: None,
+            bearer_token: None,
             host_ip: es_ip,
             host_port: es_port.map(|p| p.parse::<u16>().unwrap()),
             host_scheme: Some("https".to_string()),
             cert_path: None,
-            // verify_certs: Some(false),
-            // ca_certs: None,
-            // ssl_show_warn: Some(false),
+            nodes: vec![],
+            cloud_id: None,
+            sniff_interval: None,
+            insecure: true,
+            client_cert_path: None,
+            client_key_path: None,
         };
 
         let es_host = Host::new(config);
@@ -0,0 +1,168 @@
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+
+use crate::aggs::fetch_aggs_page;
+use crate::elastic::SharedClient;
+
+// the 'health' of the index: ideally every path has exactly one record
+#[derive(Debug, Default, Serialize)]
+pub struct HealthReport {
+    pub index: String,
+    pub total_paths: u64,
+    pub duplicate_paths: u64,
+    pub duplicate_records: u64,
+    // doc_count -> number of paths with that many records
+    pub histogram: BTreeMap<u64, u64>,
+    // paths with the most records, worst first
+    pub worst_paths: Vec<WorstPath>,
+    #[serde(skip)]
+    top: usize,
+    #[serde(skip)]
+    worst_heap: BinaryHeap<Reverse<(u64, String)>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorstPath {
+    pub path: String,
+    pub doc_count: u64,
+}
+
+impl HealthReport {
+    pub fn new(index: &str, top: usize) -> Self {
+        Self {
+            index: index.to_string(),
+            top,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_bucket(&mut self, path: &str, doc_count: u64) {
+        self.total_paths += 1;
+        *self.histogram.entry(doc_count).or_insert(0) += 1;
+
+        if doc_count <= 1 {
+            return;
+        }
+
+        self.duplicate_paths += 1;
+        self.duplicate_records += doc_count - 1;
+
+        // keep only the top N, the smallest of them sits on top of the heap
+        if self.top == 0 {
+            return;
+        }
+        self.worst_heap.push(Reverse((doc_count, path.to_string())));
+        if self.worst_heap.len() > self.top {
+            self.worst_heap.pop();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        let mut worst: Vec<(u64, String)> = std::mem::take(&mut self.worst_heap)
+            .into_iter()
+            .map(|Reverse(entry)| entry)
+            .collect();
+        worst.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        self.worst_paths = worst
+            .into_iter()
+            .map(|(doc_count, path)| WorstPath { path, doc_count })
+            .collect();
+    }
+}
+
+pub async fn health_report(
+    client: SharedClient,
+    index: &str,
+    page_size: usize,
+    top: usize,
+) -> Result<HealthReport, Box<dyn std::error::Error>> {
+    let client = client.get();
+
+    let mut report = HealthReport::new(index, top);
+    let mut after = String::new();
+
+    loop {
+        let (aggs, after_key) = match fetch_aggs_page(&client, index, page_size, &after).await? {
+            Some(page) => page,
+            None => return Err("Failed to read aggregation page".into()),
+        };
+
+        if aggs.is_empty() {
+            break;
+        }
+
+        for bucket in &aggs {
+            report.add_bucket(&bucket.key.file, bucket.doc_count);
+        }
+
+        after = after_key;
+    }
+
+    report.finish();
+
+    Ok(report)
+}
+
+pub fn print_report(report: &HealthReport) {
+    println!("{:<24}{}", "Index:", report.index);
+    println!("{:<24}{}", "Paths:", report.total_paths);
+    println!("{:<24}{}", "Paths with duplicates:", report.duplicate_paths);
+    println!("{:<24}{}", "Surplus records:", report.duplicate_records);
+
+    println!();
+    println!("{:>12}  {:>12}", "records", "paths");
+    for (doc_count, paths) in &report.histogram {
+        println!("{:>12}  {:>12}", doc_count, paths);
+    }
+
+    if !report.worst_paths.is_empty() {
+        println!();
+        println!("{:>12}  path", "records");
+        for worst in &report.worst_paths {
+            println!("{:>12}  {}", worst.doc_count, worst.path);
+        }
+    }
+}
+
+pub fn print_report_json(report: &HealthReport) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_counts_duplicates_and_keeps_worst_paths() {
+        let mut report = HealthReport::new("index", 2);
+
+        report.add_bucket("/a", 1);
+        report.add_bucket("/b", 3);
+        report.add_bucket("/c", 7);
+        report.add_bucket("/d", 1);
+        report.add_bucket("/e", 5);
+        report.finish();
+
+        assert_eq!(report.total_paths, 5);
+        assert_eq!(report.duplicate_paths, 3);
+        assert_eq!(report.duplicate_records, 2 + 6 + 4);
+        assert_eq!(report.histogram.get(&1), Some(&2));
+        assert_eq!(report.histogram.get(&7), Some(&1));
+        assert_eq!(
+            report.worst_paths,
+            vec![
+                WorstPath {
+                    path: "/c".to_string(),
+                    doc_count: 7
+                },
+                WorstPath {
+                    path: "/e".to_string(),
+                    doc_count: 5
+                },
+            ]
+        );
+    }
+}
@@ -125,7 +125,7 @@ pub fn initialize_logging(in_directory: &str, log_to_console: bool) -> Result<()
     }
     std::fs::create_dir_all(directory.clone())?;
     let log_path = directory.join(LOG_FILE.clone());
-    println!("Logging to: {:?}", &log_path);
+    eprintln!("Logging to: {:?}", &log_path);
     let log_file = std::fs::File::create(log_path)?;
 
     // let offset = time::UtcOffset::current_local_offset().unwrap_or(time::UtcOffset::UTC);
@@ -159,18 +159,20 @@ pub fn initialize_logging(in_directory: &str, log_to_console: bool) -> Result<()
         //     offset,
         //     time::format_description::well_known::Rfc3339,
         // );
+        // stderr, stdout carries the reports (health --format json, plan) that get piped elsewhere
         let console_subscriber = tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
             .with_timer(LocalTime)
             .with_target(false)
             .with_ansi(true)
             .with_filter(tracing_subscriber::filter::EnvFilter::from_default_env());
 
-        let _registry = tracing_subscriber::registry()
+        tracing_subscriber::registry()
         .with(file_subscriber)
         .with(console_subscriber)
         .with(ErrorLayer::default()).init();
     } else {
-        let _registry = tracing_subscriber::registry()
+        tracing_subscriber::registry()
             .with(file_subscriber)
             .with(ErrorLayer::default()).init();
     };
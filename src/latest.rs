@@ -1,22 +1,128 @@
-use elasticsearch::SearchParts;
+use elasticsearch::{Elasticsearch, SearchParts};
 use serde_json::{json, Value};
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio::time::{Duration, Instant};
 // use tracing::field;
 
-use crate::elastic::create_client;
-use crate::elastic::Host;
-use crate::message::Message;
+use crate::dead_letter::DeadLetters;
+use crate::elastic::{describe_response, SharedClient};
+use crate::message::{LatestHit, Message};
+use crate::metrics::Histogram;
 
 // TODO use json! macro to create the query
 
+#[derive(Debug, Default)]
+pub struct LookupStats {
+    pub lookups: AtomicU64,
+    // failed lookup requests
+    pub errors: AtomicU64,
+    pub latency: Histogram,
+}
+
+impl LookupStats {
+    fn record(&self, duration: Duration, ok: bool) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(duration);
+    }
+}
+
 pub async fn get_last_event_for_record(
-    es_host: Host,
+    client: SharedClient,
     index: &str,
     record: &str,
+    permit: OwnedSemaphorePermit,
+    dead_letters: DeadLetters,
     tx: mpsc::Sender<Message>,
+    stats: Arc<LookupStats>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let client = create_client(es_host)?;
+    let client = client.get();
+
+    // scoped, the error must not be held across the send below
+    let response_body = {
+        let started = Instant::now();
+        let result = query_last_event(&client, index, record).await;
+        stats.record(started.elapsed(), result.is_ok());
+        result?
+    };
+
+    let raw = match first_hit(&response_body)? {
+        Some(raw) => raw.clone(),
+        None => return Err(format!("No records found for {}", record).into()),
+    };
+
+    let hit = match serde_json::from_value(raw.clone()) {
+        Ok(hit) => with_known_file_type(hit, &response_body),
+        Err(e) => {
+            dead_letters.write(&format!("Malformed latest record: {}", e), &raw)?;
+            return Ok(());
+        }
+    };
 
+    let message = Message::LastRecord { hit, raw, permit };
+
+    tx.send(message).await?;
+
+    Ok(())
+}
+
+// the latest record of a path, None when the path has no records,
+// a hit that is missing required fields is an error
+pub async fn query_latest_hit(
+    client: &Elasticsearch,
+    index: &str,
+    record: &str,
+) -> Result<Option<LatestHit>, Box<dyn std::error::Error>> {
+    let response_body = query_last_event(client, index, record).await?;
+
+    let hit = match first_hit(&response_body)? {
+        Some(hit) => hit,
+        None => return Ok(None),
+    };
+
+    let hit = serde_json::from_value(hit.clone())
+        .map_err(|e| format!("Malformed latest record for {}: {}: {}", record, e, hit))?;
+
+    Ok(Some(with_known_file_type(hit, &response_body)))
+}
+
+// deleted and moved events carry no file.type, the path was already gone when it was recorded,
+// the type then comes from the newest record of the path that still has one
+pub fn with_known_file_type(mut hit: LatestHit, response_body: &Value) -> LatestHit {
+    if !hit.source.file.file_type.is_empty() {
+        return hit;
+    }
+    let known = &response_body["aggregations"]["known_type"]["latest"]["hits"]["hits"][0]
+        ["_source"]["file"]["type"];
+    hit.source.file.file_type = match known {
+        Value::String(file_type) => vec![file_type.clone()],
+        Value::Array(file_types) => file_types
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        _ => vec![],
+    };
+    hit
+}
+
+// hits.hits[0] of a search response, None when nothing matched
+fn first_hit(response_body: &Value) -> Result<Option<&Value>, Box<dyn std::error::Error>> {
+    match response_body["hits"]["hits"].as_array() {
+        Some(hits) => Ok(hits.first()),
+        None => Err(format!("Unexpected search response: {}", response_body).into()),
+    }
+}
+
+pub async fn query_last_event(
+    client: &Elasticsearch,
+    index: &str,
+    record: &str,
+) -> Result<Value, Box<dyn std::error::Error>> {
     let page_size = 1; // only get the last event
 
     let fields = vec![
@@ -38,7 +144,22 @@ pub async fn get_last_event_for_record(
                 { "file.uri" : record }}
                 ]
             }
-          }
+          },
+        // see with_known_file_type
+        "aggs": {
+            "known_type": {
+                "filter": {"exists": {"field": "file.type"}},
+                "aggs": {
+                    "latest": {
+                        "top_hits": {
+                            "size": 1,
+                            "_source": ["file.type"],
+                            "sort": [{"@timestamp": {"order": "desc"}}]
+                        }
+                    }
+                }
+            }
+        }
     })
     .to_string();
 
@@ -52,19 +173,10 @@ pub async fn get_last_event_for_record(
         .send()
         .await?;
 
-    log::debug!("Response from ES: {:?}", response);
+    log::debug!("Response from ES: {}", describe_response(&response));
 
-    let response_body = match response.json::<Value>().await {
-        Ok(body) => body,
-        Err(_) => return Err("Failed to get last event for record".into()),
-    };
-
-    let message = Message::LastRecord {
-        event_type: "last_record".to_string(),
-        payload: response_body.clone(),
-    };
-
-    tx.send(message).await?;
-
-    Ok(())
+    match response.json::<Value>().await {
+        Ok(body) => Ok(body),
+        Err(_) => Err("Failed to get last event for record".into()),
+    }
 }
@@ -1,98 +1,207 @@
+use clap::Parser;
 use dotenv::dotenv;
-use std::env;
 
 pub mod aggs;
 pub mod app;
+pub mod bulk_delete;
+pub mod cli;
+pub mod config;
+pub mod dead_letter;
 pub mod delete_records;
+pub mod delete_tasks;
 pub mod elastic;
+pub mod health;
 pub mod init_logging;
 pub mod latest;
 pub mod message;
+pub mod metrics;
 pub mod parse_record;
+pub mod plan;
+pub mod preflight;
+pub mod probe;
+pub mod server;
+pub mod supervisor;
+pub mod validate;
 
 use crate::app::App;
+use crate::cli::{Cli, Command, OutputFormat, RunArgs};
+use crate::config::{Config, Secret};
 use crate::init_logging::initialize_logging;
 
-async fn tokio_main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().ok();
-
-    let log_path = env::var("CONDENSE_LOG_PATH").unwrap_or_else(|_| "log".to_string());
-
-    let log_to_console = env::var("CONDENSE_LOG_CONSOLE")
-        .unwrap_or_else(|_| "true".to_string())
-        .parse::<bool>()?;
+fn es_host(config: &Config) -> elastic::Host {
+    elastic::Host::new(elastic::HostConfig::from(&config.elasticsearch))
+}
 
-    initialize_logging(&log_path, log_to_console)?;
+fn check_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let condense = &config.condense;
+    let elasticsearch = &config.elasticsearch;
+
+    let url = es_host(config).describe()?;
+
+    let set = |value: &Option<Secret>| if value.is_some() { "<set>" } else { "-" };
+
+    println!("{:<20}{}", "Elasticsearch:", url);
+    println!("{:<20}{}", "Sniff nodes:", elasticsearch.sniff);
+    println!("{:<20}{}", "Auth:", elasticsearch.auth_mode()?);
+    println!(
+        "{:<20}{}",
+        "User:",
+        elasticsearch.user.as_deref().unwrap_or("-")
+    );
+    println!("{:<20}{}", "Password:", set(&elasticsearch.password));
+    println!(
+        "{:<20}{}",
+        "API key id:",
+        elasticsearch.api_key_id.as_deref().unwrap_or("-")
+    );
+    println!("{:<20}{}", "API key:", set(&elasticsearch.api_key));
+    println!(
+        "{:<20}{}",
+        "Bearer token:",
+        set(&elasticsearch.bearer_token)
+    );
+    let verify = match (&elasticsearch.cert_path, elasticsearch.insecure) {
+        (_, true) => "DISABLED (insecure)",
+        (Some(cert_path), false) => cert_path.as_str(),
+        (None, false) => "system trust store",
+    };
+    println!("{:<20}{}", "Verify TLS:", verify);
+    println!(
+        "{:<20}{}",
+        "Client cert:",
+        elasticsearch.client_cert_path.as_deref().unwrap_or("-")
+    );
+    println!("{:<20}{}", "Index:", condense.index);
+    println!("{:<20}{}", "Page size:", condense.page_size);
+    println!("{:<20}{}", "Action buffer:", condense.action_buffer);
+    println!("{:<20}{}", "Max in flight:", condense.max_in_flight);
+    println!("{:<20}{}", "Delete buffer:", condense.delete_buffer);
+    println!("{:<20}{}s", "Delete timeout:", condense.delete_timeout);
+    println!("{:<20}{}", "Delete mode:", condense.delete_mode);
+    println!("{:<20}{}", "Bulk size:", condense.bulk_size);
+    println!("{:<20}{}", "Max clauses:", condense.max_clauses);
+    println!("{:<20}{}", "Delete retries:", condense.delete_retries);
+    println!("{:<20}{}", "Async delete:", condense.async_delete);
+    println!("{:<20}{}", "Delete slices:", condense.delete_slices);
+    println!(
+        "{:<20}{}",
+        "Requests/s:",
+        condense
+            .requests_per_second
+            .map_or("-".to_string(), |rps| rps.to_string())
+    );
+    println!("{:<20}{}", "Task file:", condense.task_file);
+    println!("{:<20}{}", "Preflight:", condense.preflight);
+    println!(
+        "{:<20}{}",
+        "Listen address:",
+        condense.listen_addr.as_deref().unwrap_or("-")
+    );
+    println!("{:<20}{}s", "Stall timeout:", condense.stall_timeout);
+    println!(
+        "{:<20}{}s",
+        "Aggregation sleep:", condense.aggregation_sleep
+    );
+    println!("{:<20}{}", "Dry run:", condense.dry_run);
+    println!("{:<20}{}s", "Shutdown timeout:", condense.shutdown_timeout);
+    println!("{:<20}{}", "Plan file:", condense.plan_file);
+    println!("{:<20}{}", "Dead-letter file:", condense.dead_letter_file);
+    println!("{:<20}{}", "Log path:", condense.log_path);
+    println!("{:<20}{}", "Log to console:", condense.log_to_console);
 
-    let index =
-        env::var("CONDENSE_INDEX").unwrap_or_else(|_| ".ds-logs-fim.event-default*".to_string());
+    Ok(())
+}
 
-    let action_buffer_size = env::var("CONDENSE_ACTION_BUFFER_SIZE")
-        .unwrap_or_else(|_| "1024".to_string())
-        .parse::<usize>()?;
+async fn tokio_main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv().ok();
 
-    let page_size = env::var("CONDENSE_PAGE_SIZE")
-        .unwrap_or_else(|_| "10".to_string())
-        .parse::<usize>()?;
+    let cli = Cli::parse();
 
-    let buffer_size = env::var("CONDENSE_DELETE_BUFFER")
-        .unwrap_or_else(|_| "100".to_string())
-        .parse::<usize>()?;
+    let mut config = Config::load(cli.config.as_deref())?;
 
-    let del_timeout = env::var("CONDENSE_DELETE_TIMEOUT")
-        .unwrap_or_else(|_| "5".to_string())
-        .parse::<u64>()?;
+    let command = cli.command.unwrap_or(Command::Run(RunArgs::default()));
 
-    let agg_sleep = env::var("CONDENSE_AGGREGATION_SLEEP")
-        .unwrap_or_else(|_| "20".to_string())
-        .parse::<u64>()?;
+    match &command {
+        Command::Run(args)
+        | Command::Once(args)
+        | Command::CheckConfig(args)
+        | Command::Preflight(args) => config.apply_run_args(args),
+        Command::Health(args) => config.apply_connection_args(&args.connection),
+        Command::Plan(args) | Command::Apply(args) => {
+            config.apply_connection_args(&args.connection);
+            if let Some(plan_file) = &args.plan_file {
+                config.condense.plan_file = plan_file.clone();
+            }
+        }
+    }
 
-    let es_ip = env::var("ES_IP").ok();
-    let es_port = env::var("ES_PORT").ok();
+    config.validate()?;
 
-    let cert_path = env::var("CERT_PATH").ok();
+    // check-config only prints, it should not create a log file
+    if let Command::CheckConfig(_) = command {
+        return check_config(&config);
+    }
 
-    let es_user = env::var("ES_USER").ok();
-    let es_password = env::var("ES_PASSWORD").ok();
+    let condense = &config.condense;
 
-    let config = elastic::HostConfig {
-        user: es_user.clone(),
-        password: es_password.clone(),
-        host_ip: es_ip.clone(),
-        host_port: es_port.map(|p| p.parse::<u16>().unwrap()),
-        host_scheme: Some("https".to_string()),
-        cert_path,
-        // verify_certs: Some(false),
-        // ca_certs: None,
-        // ssl_show_warn: Some(true),
-    };
+    initialize_logging(&condense.log_path, condense.log_to_console)?;
 
-    let es_host = elastic::Host::new(config);
+    // TODO initialize_panic_handler()?;
 
-    // let es_host = elastic::Host::new(
-    //     es_user,
-    //     es_password,
-    //     es_ip,
-    //     es_port.map(|p| p.parse::<u16>().unwrap()),
-    //     Some("https".to_string()),
-    //     cert_path,
-    //     Some(false),
-    //     None,
-    //     Some(false),
-    // );
+    if config.elasticsearch.insecure {
+        log::warn!("TLS certificate verification is disabled, the identity of the Elasticsearch nodes is not checked");
+    }
 
-    // TODO initialize_panic_handler()?;
+    // a misconfiguration is reported once here, not as workers failing over and over
+    let preflight_required = match command {
+        Command::Run(_) | Command::Once(_) => condense.preflight,
+        Command::Preflight(_) => true,
+        _ => false,
+    };
+    if preflight_required {
+        let report =
+            preflight::run_preflight(&elastic::SharedClient::new(es_host(&config))?, &config).await;
+        preflight::print_report(&report);
+        if !report.passed() {
+            return Err("Preflight checks failed".into());
+        }
+    }
 
-    let mut app = App::new(
-        es_host,
-        action_buffer_size,
-        &index,
-        page_size,
-        buffer_size,
-        del_timeout,
-        agg_sleep,
-    )?;
-    app.run().await?;
+    match command {
+        Command::Run(_) => App::new(&config)?.run().await?,
+        Command::Once(_) => App::new(&config)?.run_once().await?,
+        Command::Health(args) => {
+            let report = health::health_report(
+                elastic::SharedClient::new(es_host(&config))?,
+                &condense.index,
+                condense.page_size,
+                args.top,
+            )
+            .await?;
+            match args.format {
+                OutputFormat::Table => health::print_report(&report),
+                OutputFormat::Json => health::print_report_json(&report)?,
+            }
+        }
+        Command::Plan(_) => {
+            plan::write_plan(
+                elastic::SharedClient::new(es_host(&config))?,
+                &condense.index,
+                condense.page_size,
+                &condense.plan_file,
+            )
+            .await?
+        }
+        Command::Apply(_) => {
+            plan::apply_plan(
+                elastic::SharedClient::new(es_host(&config))?,
+                &condense.index,
+                &condense.plan_file,
+            )
+            .await?
+        }
+        Command::CheckConfig(_) | Command::Preflight(_) => {}
+    }
 
     Ok(())
 }
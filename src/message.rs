@@ -1,8 +1,139 @@
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
+use tokio::sync::OwnedSemaphorePermit;
 
+// Aggregate and LastRecord carry the in-flight permit of their path,
+// it is released once the directive parsed from the record is in the delete queue
+// LastRecord keeps the hit as elasticsearch returned it for the dead-letter file
 #[derive(Debug)]
 pub enum Message {
-    Aggregate { event_type: String, payload: Value },
-    LastRecord { event_type: String, payload: Value },
-    Delete { event_type: String, payload: Value },
+    Aggregate {
+        bucket: AggBucket,
+        permit: OwnedSemaphorePermit,
+    },
+    LastRecord {
+        hit: LatestHit,
+        raw: Value,
+        permit: OwnedSemaphorePermit,
+    },
+}
+
+// one bucket of the composite aggregation on file.uri
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AggBucket {
+    pub key: AggKey,
+    pub doc_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AggKey {
+    pub file: String,
+}
+
+// the latest record of a path, hits.hits[0] of the latest event search
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LatestHit {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(rename = "_index")]
+    pub index: String,
+    #[serde(rename = "_source")]
+    pub source: LatestSource,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LatestSource {
+    #[serde(rename = "@timestamp")]
+    pub timestamp: String,
+    pub file: FileFields,
+    #[serde(default)]
+    pub event: EventFields,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FileFields {
+    pub uri: String,
+    // file, dir, symlink
+    #[serde(rename = "type", default, deserialize_with = "one_or_many")]
+    pub file_type: Vec<String>,
+}
+
+// event.action / event.type are keyword arrays in the fim index, single values are accepted too
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct EventFields {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub action: Vec<String>,
+    #[serde(default, rename = "type", deserialize_with = "one_or_many")]
+    pub event_type: Vec<String>,
+}
+
+impl LatestHit {
+    pub fn action(&self) -> &str {
+        self.source.event.action.first().map_or("", String::as_str)
+    }
+
+    pub fn event_type(&self) -> &str {
+        self.source
+            .event
+            .event_type
+            .first()
+            .map_or("", String::as_str)
+    }
+
+    pub fn file_type(&self) -> &str {
+        self.source
+            .file
+            .file_type
+            .first()
+            .map_or("", String::as_str)
+    }
+
+    // the path itself is gone, every record of it can be deleted
+    pub fn is_removal(&self) -> bool {
+        matches!(self.action(), "deleted" | "moved")
+    }
+
+    pub fn is_directory(&self) -> bool {
+        matches!(self.file_type(), "dir" | "directory")
+    }
+}
+
+// what the delete task should do for one path
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeleteDirective {
+    pub file_path: String,
+    pub event_type: String,
+    // the record to keep, None when the path was deleted or moved
+    pub keep: Option<RecordRef>,
+    // also delete everything below the path, only for deleted or moved directories
+    // (or removals of a path whose type was never recorded)
+    pub cascade: bool,
+    // @timestamp of the record the directive is based on, newer documents are never deleted
+    pub timestamp: String,
+    // failed delete attempts so far
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RecordRef {
+    pub id: String,
+    pub index: String,
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
 }
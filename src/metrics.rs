@@ -0,0 +1,272 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::WeakSender;
+
+use crate::aggs::AggStats;
+use crate::delete_records::DeleteStats;
+use crate::latest::LookupStats;
+use crate::message::Message;
+use crate::supervisor::SupervisorStats;
+
+// the stats every part of the pipeline keeps, rendered in the prometheus text format on /metrics
+
+// upper bounds in seconds, a latest lookup is a single small search
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug)]
+pub struct Histogram {
+    // per bucket, not cumulative, the last one is +Inf
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..=LATENCY_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        cumulative += self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count.load(Ordering::Relaxed));
+    }
+}
+
+pub struct Metrics {
+    pub aggs: Arc<AggStats>,
+    pub lookups: Arc<LookupStats>,
+    pub deletes: Arc<DeleteStats>,
+    pub supervisor: Arc<SupervisorStats>,
+    // weak, a scrape must not keep the event channel open
+    pub events: WeakSender<Message>,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+        let aggs = &self.aggs;
+        counter(
+            &mut out,
+            "condense_agg_pages_total",
+            "Composite aggregation pages fetched",
+            load(&aggs.pages),
+        );
+        counter(
+            &mut out,
+            "condense_agg_buckets_total",
+            "Composite aggregation buckets scanned",
+            load(&aggs.buckets),
+        );
+        counter(
+            &mut out,
+            "condense_duplicate_paths_total",
+            "Paths found with more than one record",
+            load(&aggs.duplicate_paths),
+        );
+        counter(
+            &mut out,
+            "condense_agg_passes_total",
+            "Full aggregation passes completed",
+            load(&aggs.passes),
+        );
+        gauge(
+            &mut out,
+            "condense_agg_last_pass_seconds",
+            "Duration of the last full aggregation pass",
+            load(&aggs.last_pass_millis) as f64 / 1000.0,
+        );
+
+        let lookups = &self.lookups;
+        counter(
+            &mut out,
+            "condense_latest_lookups_total",
+            "Lookups of the latest record of a path",
+            load(&lookups.lookups),
+        );
+        counter(
+            &mut out,
+            "condense_latest_lookup_errors_total",
+            "Lookups of the latest record of a path that failed",
+            load(&lookups.errors),
+        );
+        lookups.latency.render(
+            &mut out,
+            "condense_latest_lookup_duration_seconds",
+            "Duration of the lookup of the latest record of a path",
+        );
+
+        let deletes = &self.deletes;
+        counter(
+            &mut out,
+            "condense_delete_directives_buffered_total",
+            "Delete directives received by the delete buffer",
+            load(&deletes.directives_buffered),
+        );
+        header(
+            &mut out,
+            "condense_delete_flushes_total",
+            "Delete buffer flushes by trigger",
+            "counter",
+        );
+        for (trigger, value) in [
+            ("timeout", &deletes.flushes_timeout),
+            ("buffer_full", &deletes.flushes_buffer_full),
+            ("channel_closed", &deletes.flushes_channel_closed),
+        ] {
+            let _ = writeln!(
+                out,
+                "condense_delete_flushes_total{{trigger=\"{}\"}} {}",
+                trigger,
+                load(value)
+            );
+        }
+        counter(
+            &mut out,
+            "condense_documents_deleted_total",
+            "Documents deleted",
+            load(&deletes.deleted),
+        );
+        counter(
+            &mut out,
+            "condense_delete_version_conflicts_total",
+            "Documents not deleted because of a version conflict",
+            load(&deletes.version_conflicts),
+        );
+        counter(
+            &mut out,
+            "condense_delete_failures_total",
+            "Failed delete requests and bulk items",
+            load(&deletes.failures),
+        );
+        counter(
+            &mut out,
+            "condense_paths_requeued_total",
+            "Paths requeued after a failed delete",
+            load(&deletes.paths_requeued),
+        );
+        counter(
+            &mut out,
+            "condense_paths_dropped_total",
+            "Paths given up after all delete retries",
+            load(&deletes.paths_dropped),
+        );
+        counter(
+            &mut out,
+            "condense_paths_not_enqueued_total",
+            "Delete directives that never reached the delete queue",
+            load(&deletes.not_enqueued),
+        );
+
+        let supervisor = &self.supervisor;
+        counter(
+            &mut out,
+            "condense_tasks_failed_total",
+            "Lookup, parse and delete tasks that returned an error",
+            load(&supervisor.tasks_failed),
+        );
+        counter(
+            &mut out,
+            "condense_tasks_panicked_total",
+            "Lookup, parse and delete tasks that panicked",
+            load(&supervisor.tasks_panicked),
+        );
+        counter(
+            &mut out,
+            "condense_worker_restarts_total",
+            "Restarts of the aggregation and delete workers",
+            load(&supervisor.worker_restarts),
+        );
+
+        // nothing to report once the channel is closed
+        if let Some(events) = self.events.upgrade() {
+            gauge(
+                &mut out,
+                "condense_event_channel_depth",
+                "Messages waiting in the event channel",
+                (events.max_capacity() - events.capacity()) as f64,
+            );
+            gauge(
+                &mut out,
+                "condense_event_channel_capacity",
+                "Size of the event channel",
+                events.max_capacity() as f64,
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(40));
+        histogram.observe(Duration::from_secs(30));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "lookup_seconds", "Lookups");
+
+        assert!(out.contains("# TYPE lookup_seconds histogram\n"));
+        assert!(out.contains("lookup_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("lookup_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(out.contains("lookup_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(out.contains("lookup_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("lookup_seconds_sum 30.043\n"));
+        assert!(out.contains("lookup_seconds_count 3\n"));
+    }
+}
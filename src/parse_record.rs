@@ -1,87 +1,174 @@
-use serde_json::json;
 use serde_json::Value;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
-use crate::message::Message;
+use crate::dead_letter::DeadLetters;
+use crate::delete_records::DeleteStats;
+use crate::message::{DeleteDirective, LatestHit, RecordRef};
+use crate::validate::validate_directive;
 
+// incomplete directives go to the dead-letter file instead of the delete buffer
+// the send waits while the delete queue is full, that is what slows aggregation down
 pub async fn parse_record(
-    record: Value,
-    tx: mpsc::Sender<Message>,
+    hit: LatestHit,
+    raw: Value,
+    dead_letters: DeadLetters,
+    delete_tx: mpsc::Sender<DeleteDirective>,
+    stats: Arc<DeleteStats>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let last_event = record;
-
-    let event_action = last_event
-        .get("hits")
-        .and_then(|v| v.get("hits"))
-        .and_then(|v| v.get(0))
-        .and_then(|v| v.get("_source"))
-        .and_then(|v| v.get("event"))
-        .and_then(|v| v.get("action"))
-        .and_then(|v| v.get(0))
-        .and_then(|v| v.as_str())
-        .unwrap_or("");
-
-    let event_type = last_event
-        .get("hits")
-        .and_then(|v| v.get("hits"))
-        .and_then(|v| v.get(0))
-        .and_then(|v| v.get("_source"))
-        .and_then(|v| v.get("event"))
-        .and_then(|v| v.get("type"))
-        .and_then(|v| v.get(0))
-        .and_then(|v| v.as_str())
-        .unwrap_or("");
-
-    let file_path = last_event
-        .get("hits")
-        .and_then(|v| v.get("hits"))
-        .and_then(|v| v.get(0))
-        .and_then(|v| v.get("_source"))
-        .and_then(|v| v.get("file"))
-        .and_then(|v| v.get("uri"))
-        .and_then(|v| v.as_str())
-        .unwrap_or("empty_file_path");
-
-    let record_id_and_index = if event_action == "moved" || event_action == "deleted" {
-        ("no_id".to_string(), "no_index".to_string())
+    let directive = parse_last_event(&hit);
+
+    if let Err(reason) = validate_directive(&directive) {
+        dead_letters.write(&reason, &raw)?;
+        return Ok(());
+    }
+
+    if let Err(e) = delete_tx.send(directive).await {
+        stats.not_enqueued.fetch_add(1, Ordering::Relaxed);
+        return Err(format!(
+            "Delete queue closed, dropped directive for {}",
+            e.0.file_path
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// turns the latest record of a path into a delete directive,
+// the latest record is kept unless the path was deleted or moved
+// only a deleted or moved directory takes the records below it along,
+// the children of a directory that was merely touched may still be current
+// a removal whose type is unknown cascades as well, the prefix only matches paths below it
+pub fn parse_last_event(hit: &LatestHit) -> DeleteDirective {
+    let keep = if hit.is_removal() {
+        None
     } else {
-        let record_id = last_event
-            .get("hits")
-            .and_then(|v| v.get("hits"))
-            .and_then(|v| v.get(0))
-            .and_then(|v| v.get("_id"))
-            .and_then(|v| v.as_str())
-            .unwrap_or("empty_record_id");
-
-        let record_index = last_event
-            .get("hits")
-            .and_then(|v| v.get("hits"))
-            .and_then(|v| v.get(0))
-            .and_then(|v| v.get("_index"))
-            .and_then(|v| v.as_str())
-            .unwrap_or("empty_record_index");
-
-        (record_id.to_string(), record_index.to_string())
+        Some(RecordRef {
+            id: hit.id.clone(),
+            index: hit.index.clone(),
+        })
     };
 
-    // println!("File Path: {:?}", file_path);
-    // println!("Record ID: {:?}", record_id_and_index);
+    let directive = DeleteDirective {
+        file_path: hit.source.file.uri.clone(),
+        event_type: hit.event_type().to_string(),
+        keep,
+        cascade: hit.is_removal() && (hit.is_directory() || hit.file_type().is_empty()),
+        timestamp: hit.source.timestamp.clone(),
+        attempts: 0,
+    };
 
-    let payload = json!({
-        "event_type": event_type,
-        "file_path": file_path,
-        "record_id": record_id_and_index.0,
-        "record_index": record_id_and_index.1,
-    });
+    log::debug!("Parsed record: {:?}", directive);
 
-    log::debug!("Parsed record: {}", payload);
+    directive
+}
 
-    let message = Message::Delete {
-        event_type: event_type.to_string(),
-        payload,
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::latest::with_known_file_type;
+    use serde_json::json;
 
-    tx.send(message).await?;
+    // shaped like events_notes/file_created_deleted: single file.type, array event fields,
+    // and no file.type at all on deletions
+    fn record(action: &str, event_type: &str, file_type: Option<&str>) -> Value {
+        let mut file = json!({"uri": "/srv/data", "parent_path": "/srv"});
+        if let Some(file_type) = file_type {
+            file["type"] = json!(file_type);
+        }
+        json!({
+            "_id": "abc",
+            "_index": ".ds-logs-fim.event-default-2024.03.26-000002",
+            "_source": {
+                "@timestamp": "2024-03-27T18:02:36.021Z",
+                "file": file,
+                "event": {"action": [action], "type": [event_type]}
+            }
+        })
+    }
 
-    Ok(())
+    // the latest event search: the newest record, and the newest one that still has file.type
+    fn latest(newest: Value, typed: Option<Value>) -> LatestHit {
+        let typed_hits: Vec<Value> = typed.into_iter().collect();
+        let response_body = json!({
+            "hits": {"hits": [newest.clone()]},
+            "aggregations": {"known_type": {"latest": {"hits": {"hits": typed_hits}}}}
+        });
+        with_known_file_type(serde_json::from_value(newest).unwrap(), &response_body)
+    }
+
+    fn kept() -> Option<RecordRef> {
+        Some(RecordRef {
+            id: "abc".to_string(),
+            index: ".ds-logs-fim.event-default-2024.03.26-000002".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_modified_paths_are_condensed_without_cascade() {
+        for file_type in ["file", "dir"] {
+            let newest = record("modified", "change", Some(file_type));
+            let directive = parse_last_event(&latest(newest.clone(), Some(newest)));
+            assert_eq!(directive.file_path, "/srv/data");
+            assert_eq!(directive.event_type, "change");
+            assert_eq!(directive.timestamp, "2024-03-27T18:02:36.021Z");
+            assert_eq!(directive.keep, kept());
+            assert!(!directive.cascade);
+        }
+    }
+
+    #[test]
+    fn test_created_directory_does_not_cascade() {
+        let newest = record("created", "creation", Some("dir"));
+        let directive = parse_last_event(&latest(newest.clone(), Some(newest)));
+        assert_eq!(directive.keep, kept());
+        assert!(!directive.cascade);
+    }
+
+    #[test]
+    fn test_deleted_or_moved_file_keeps_nothing_without_cascade() {
+        for (action, event_type) in [("deleted", "deletion"), ("moved", "change")] {
+            let hit = latest(
+                record(action, event_type, None),
+                Some(record("created", "creation", Some("file"))),
+            );
+            let directive = parse_last_event(&hit);
+            assert_eq!(directive.keep, None);
+            assert!(!directive.cascade);
+        }
+    }
+
+    #[test]
+    fn test_deleted_or_moved_directory_cascades() {
+        for (action, event_type) in [("deleted", "deletion"), ("moved", "change")] {
+            let hit = latest(
+                record(action, event_type, None),
+                Some(record("created", "creation", Some("dir"))),
+            );
+            assert_eq!(hit.file_type(), "dir");
+            let directive = parse_last_event(&hit);
+            assert_eq!(directive.keep, None);
+            assert!(directive.cascade);
+        }
+    }
+
+    #[test]
+    fn test_deletion_of_unknown_type_cascades() {
+        let directive = parse_last_event(&latest(record("deleted", "deletion", None), None));
+        assert_eq!(directive.keep, None);
+        assert!(directive.cascade);
+    }
+
+    #[test]
+    fn test_hit_without_file_uri_is_an_error() {
+        let hit = serde_json::from_value::<LatestHit>(json!({
+            "_id": "abc",
+            "_index": "index",
+            "_source": {"event": {"action": ["modified"]}}
+        }));
+
+        assert!(hit.is_err());
+    }
 }
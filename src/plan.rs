@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use crate::aggs::fetch_aggs_page;
+use crate::delete_records::{count_query, delete_records, generate_query, Throttle};
+use crate::elastic::SharedClient;
+use crate::latest::query_latest_hit;
+use crate::message::{DeleteDirective, LatestHit};
+use crate::parse_record::parse_last_event;
+use crate::validate::validate_directive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlanAction {
+    // keep the latest record, delete all older ones
+    Condense,
+    // the path was deleted or moved, delete every record of it (and below it for directories)
+    Delete,
+}
+
+// one line of the plan file
+// record_id / record_index point to the latest record of the path when the plan was made,
+// it is the record that is kept for condense entries
+// only documents up to its timestamp are deleted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub file_path: String,
+    pub action: PlanAction,
+    pub record_id: String,
+    pub record_index: String,
+    pub timestamp: String,
+    pub expected_deletes: u64,
+}
+
+pub async fn write_plan(
+    client: SharedClient,
+    index: &str,
+    page_size: usize,
+    plan_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client.get();
+
+    let mut writer = BufWriter::new(File::create(plan_path)?);
+
+    let mut after = String::new();
+    let mut entries = 0;
+    let mut expected_deletes = 0;
+
+    log::info!("Writing deletion plan for index {} to {}", index, plan_path);
+
+    loop {
+        let (aggs, after_key) = match fetch_aggs_page(&client, index, page_size, &after).await? {
+            Some(page) => page,
+            None => return Err("Failed to read aggregation page".into()),
+        };
+
+        if aggs.is_empty() {
+            break;
+        }
+
+        for bucket in &aggs {
+            if bucket.doc_count <= 1 {
+                continue;
+            }
+
+            let file_path = &bucket.key.file;
+
+            let hit = match query_latest_hit(&client, index, file_path).await {
+                Ok(Some(hit)) => hit,
+                Ok(None) => {
+                    log::warn!("No latest record found for {}, skipping", file_path);
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Skipping {}: {}", file_path, e);
+                    continue;
+                }
+            };
+
+            let directive = parse_last_event(&hit);
+            if let Err(reason) = validate_directive(&directive) {
+                log::warn!("Skipping {}: {}", file_path, reason);
+                continue;
+            }
+
+            let entry = plan_entry(&hit);
+
+            let query = entry_query(&directive)?;
+            let entry = PlanEntry {
+                expected_deletes: count_query(&client, index, query).await?,
+                ..entry
+            };
+
+            log::debug!("Planned: {:?}", entry);
+
+            expected_deletes += entry.expected_deletes;
+            entries += 1;
+
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+
+        after = after_key;
+    }
+
+    writer.flush()?;
+
+    log::info!(
+        "Plan written to {}: {} entries, {} documents expected to be deleted",
+        plan_path,
+        entries,
+        expected_deletes
+    );
+
+    Ok(())
+}
+
+pub async fn apply_plan(
+    client: SharedClient,
+    index: &str,
+    plan_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client.get();
+
+    let reader = BufReader::new(File::open(plan_path)?);
+
+    let mut applied = 0;
+    let mut refused = 0;
+    let mut failed = 0;
+    let mut deleted = 0;
+
+    log::info!("Applying deletion plan {} to index {}", plan_path, index);
+
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: PlanEntry = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid plan entry on line {}: {}", line_number + 1, e))?;
+
+        let hit = match query_latest_hit(&client, index, &entry.file_path).await {
+            Ok(hit) => hit,
+            Err(e) => {
+                log::warn!("Refusing plan entry for {}: {}", entry.file_path, e);
+                refused += 1;
+                continue;
+            }
+        };
+
+        // the plan is only valid as long as the record it was based on is still the latest one
+        let hit = hit.filter(|hit| {
+            let current = plan_entry(hit);
+            current.record_id == entry.record_id
+                && current.record_index == entry.record_index
+                && current.timestamp == entry.timestamp
+                && current.action == entry.action
+        });
+
+        let hit = match hit {
+            Some(hit) => hit,
+            None => {
+                log::warn!(
+                    "Refusing plan entry for {}: latest record changed since the plan was made",
+                    entry.file_path
+                );
+                refused += 1;
+                continue;
+            }
+        };
+
+        // a failed delete does not stop the entries after it
+        let response = match entry_query(&parse_last_event(&hit)) {
+            Ok(query) => delete_records(&client, index, query, &Throttle::default())
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Failed to apply plan entry for {}: {}", entry.file_path, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let entry_deleted = response.deleted;
+
+        if !response.is_complete() {
+            log::warn!(
+                "Delete for {} incomplete: status {}, {} version conflicts, {} failures, timed out: {}",
+                entry.file_path,
+                response.status,
+                response.version_conflicts,
+                response.failures.len(),
+                response.timed_out
+            );
+        }
+
+        if entry_deleted != entry.expected_deletes {
+            log::warn!(
+                "Deleted {} documents for {}, plan expected {}",
+                entry_deleted,
+                entry.file_path,
+                entry.expected_deletes
+            );
+        } else {
+            log::info!(
+                "Deleted {} documents for {}",
+                entry_deleted,
+                entry.file_path
+            );
+        }
+
+        deleted += entry_deleted;
+        applied += 1;
+    }
+
+    log::info!(
+        "Plan applied: {} entries applied, {} refused, {} failed, {} documents deleted",
+        applied,
+        refused,
+        failed,
+        deleted
+    );
+
+    if failed > 0 {
+        return Err(format!("{} plan entries failed to apply", failed).into());
+    }
+
+    Ok(())
+}
+
+// builds the plan entry for the latest record of a path, without the expected delete count
+fn plan_entry(hit: &LatestHit) -> PlanEntry {
+    let action = if hit.is_removal() {
+        PlanAction::Delete
+    } else {
+        PlanAction::Condense
+    };
+
+    PlanEntry {
+        file_path: hit.source.file.uri.clone(),
+        action,
+        record_id: hit.id.clone(),
+        record_index: hit.index.clone(),
+        timestamp: hit.source.timestamp.clone(),
+        expected_deletes: 0,
+    }
+}
+
+// the same delete query the condensing loop would send for this record
+fn entry_query(directive: &DeleteDirective) -> Result<Value, Box<dyn std::error::Error>> {
+    generate_query([directive])
+}
@@ -0,0 +1,374 @@
+use elasticsearch::http::response::Response;
+use elasticsearch::indices::{IndicesGetFieldMappingParts, IndicesResolveIndexParts};
+use elasticsearch::security::SecurityHasPrivilegesParts;
+use elasticsearch::Elasticsearch;
+use serde_json::{json, Value};
+
+use crate::config::{AuthMode, Config, DeleteMode};
+use crate::elastic::SharedClient;
+
+// checked once before the workers start, a misconfiguration would otherwise only show
+// up as workers failing and being restarted over and over
+
+// data streams and resolve_index need 7.9, the queries are tested against 7.10 and later
+const MIN_VERSION: (u64, u64) = (7, 10);
+// bulk mode pages through a point in time sorted on _shard_doc, which needs 7.12
+const BULK_MIN_VERSION: (u64, u64) = (7, 12);
+
+// fields the queries depend on and the mapping types that work for them
+const REQUIRED_FIELDS: &[(&str, &[&str])] = &[
+    ("file.uri", &["keyword"]),
+    ("@timestamp", &["date", "date_nanos"]),
+];
+
+// the mapping check lists this many offending indices at most
+const MAX_PROBLEMS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct PreflightReport {
+    pub index: String,
+    pub checks: Vec<Check>,
+}
+
+impl PreflightReport {
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Failed)
+    }
+
+    fn add(&mut self, name: &'static str, result: Result<String, String>) {
+        let (status, detail) = match result {
+            Ok(detail) => (CheckStatus::Ok, detail),
+            Err(detail) => {
+                log::error!("Preflight check {} failed: {}", name, detail);
+                (CheckStatus::Failed, detail)
+            }
+        };
+        self.checks.push(Check {
+            name,
+            status,
+            detail,
+        });
+    }
+
+    fn skip(&mut self, name: &'static str, reason: &str) {
+        self.checks.push(Check {
+            name,
+            status: CheckStatus::Skipped,
+            detail: reason.to_string(),
+        });
+    }
+}
+
+pub async fn run_preflight(client: &SharedClient, config: &Config) -> PreflightReport {
+    let client = client.get();
+    let condense = &config.condense;
+    let index = condense.index.as_str();
+
+    let mut report = PreflightReport {
+        index: index.to_string(),
+        ..Default::default()
+    };
+
+    let min_version = match condense.delete_mode {
+        DeleteMode::Bulk => BULK_MIN_VERSION,
+        DeleteMode::Query => MIN_VERSION,
+    };
+    let cluster = check_cluster(&client, min_version).await;
+    let reachable = cluster.is_ok();
+    report.add("cluster", cluster);
+    if !reachable {
+        for name in ["index", "mapping", "privileges"] {
+            report.skip(name, "cluster not reachable");
+        }
+        return report;
+    }
+
+    report.add("index", check_index(&client, index).await);
+    report.add("mapping", check_mapping(&client, index).await);
+
+    // a cluster without security has no _has_privileges
+    if config.elasticsearch.auth_mode().ok() == Some(AuthMode::None) {
+        report.skip("privileges", "auth is none");
+    } else {
+        let mut index_privileges = vec!["read"];
+        if !condense.dry_run {
+            index_privileges.push("delete");
+        }
+        // polling delete tasks goes through the tasks API
+        let cluster_privileges: &[&str] = if condense.async_delete && !condense.dry_run {
+            &["monitor"]
+        } else {
+            &[]
+        };
+        report.add(
+            "privileges",
+            check_privileges(&client, index, &index_privileges, cluster_privileges).await,
+        );
+    }
+
+    report
+}
+
+pub fn print_report(report: &PreflightReport) {
+    println!("Preflight checks for {}", report.index);
+    for check in &report.checks {
+        let status = match check.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Failed => "FAILED",
+            CheckStatus::Skipped => "skipped",
+        };
+        println!("  {:<9}{:<12}{}", status, check.name, check.detail);
+    }
+}
+
+async fn check_cluster(client: &Elasticsearch, min_version: (u64, u64)) -> Result<String, String> {
+    let body = json_body(client.info().send().await).await?;
+
+    let number = body["version"]["number"].as_str().unwrap_or_default();
+    let version = match parse_version(number) {
+        Some(version) => version,
+        None => {
+            return Err(format!(
+                "unexpected version {:?} in the info response",
+                number
+            ))
+        }
+    };
+    if version < min_version {
+        return Err(format!(
+            "elasticsearch {} is too old, {}.{} or later is required",
+            number, min_version.0, min_version.1
+        ));
+    }
+
+    Ok(format!(
+        "elasticsearch {} (cluster {})",
+        number,
+        body["cluster_name"].as_str().unwrap_or("-")
+    ))
+}
+
+async fn check_index(client: &Elasticsearch, index: &str) -> Result<String, String> {
+    let body = json_body(
+        client
+            .indices()
+            .resolve_index(IndicesResolveIndexParts::Name(&[index]))
+            .send()
+            .await,
+    )
+    .await?;
+
+    let count = |key: &str| body[key].as_array().map_or(0, Vec::len);
+    let (indices, data_streams) = (count("indices"), count("data_streams"));
+    if indices == 0 && data_streams == 0 {
+        return Err(format!("{} does not match any index", index));
+    }
+
+    Ok(format!(
+        "{} indices, {} data streams",
+        indices, data_streams
+    ))
+}
+
+async fn check_mapping(client: &Elasticsearch, index: &str) -> Result<String, String> {
+    let fields: Vec<&str> = REQUIRED_FIELDS.iter().map(|(field, _)| *field).collect();
+    let body = json_body(
+        client
+            .indices()
+            .get_field_mapping(IndicesGetFieldMappingParts::IndexFields(&[index], &fields))
+            .send()
+            .await,
+    )
+    .await?;
+
+    let indices = body.as_object().map_or(0, |indices| indices.len());
+    let problems = mapping_problems(&body);
+    if !problems.is_empty() {
+        let mut detail = problems
+            .iter()
+            .take(MAX_PROBLEMS)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("; ");
+        if problems.len() > MAX_PROBLEMS {
+            detail.push_str(&format!("; and {} more", problems.len() - MAX_PROBLEMS));
+        }
+        return Err(detail);
+    }
+
+    Ok(format!(
+        "file.uri is keyword, @timestamp is a date in {} indices",
+        indices
+    ))
+}
+
+async fn check_privileges(
+    client: &Elasticsearch,
+    index: &str,
+    index_privileges: &[&str],
+    cluster_privileges: &[&str],
+) -> Result<String, String> {
+    let body = json_body(
+        client
+            .security()
+            .has_privileges(SecurityHasPrivilegesParts::None)
+            .body(json!({
+                "cluster": cluster_privileges,
+                "index": [{"names": [index], "privileges": index_privileges}]
+            }))
+            .send()
+            .await,
+    )
+    .await?;
+
+    let user = body["username"].as_str().unwrap_or("-");
+    let missing = missing_privileges(&body);
+    if !missing.is_empty() {
+        return Err(format!("{} lacks {}", user, missing.join(", ")));
+    }
+
+    let mut granted = index_privileges.join(", ");
+    for privilege in cluster_privileges {
+        granted.push_str(&format!(", cluster {}", privilege));
+    }
+    Ok(format!("{} holds {} on {}", user, granted, index))
+}
+
+// the body of a successful response, otherwise the status and the reason elasticsearch gave
+async fn json_body(response: Result<Response, elasticsearch::Error>) -> Result<Value, String> {
+    let response = response.map_err(|e| e.to_string())?;
+    let status = response.status_code().as_u16();
+    let body = response.json::<Value>().await.map_err(|e| e.to_string())?;
+
+    if !(200..300).contains(&status) {
+        let reason = body["error"]["reason"]
+            .as_str()
+            .map_or_else(|| body.to_string(), str::to_string);
+        return Err(format!("status {}: {}", status, reason));
+    }
+    Ok(body)
+}
+
+// "8.13.0" or "8.14.0-SNAPSHOT" -> (8, 13)
+fn parse_version(number: &str) -> Option<(u64, u64)> {
+    let mut parts = number.split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+// _mapping/field response: {index: {mappings: {field: {mapping: {leaf: {type}}}}}}
+fn mapping_problems(body: &Value) -> Vec<String> {
+    let mut problems = vec![];
+    let Some(indices) = body.as_object() else {
+        return problems;
+    };
+
+    for (index, mapping) in indices {
+        for (field, types) in REQUIRED_FIELDS {
+            let leaf = field.rsplit('.').next().unwrap_or(field);
+            match mapping["mappings"][*field]["mapping"][leaf]["type"].as_str() {
+                Some(mapping_type) if types.contains(&mapping_type) => {}
+                Some(mapping_type) => problems.push(format!(
+                    "{}: {} is {}, expected {}",
+                    index,
+                    field,
+                    mapping_type,
+                    types.join(" or ")
+                )),
+                None => problems.push(format!("{}: {} is not mapped", index, field)),
+            }
+        }
+    }
+    problems
+}
+
+// _has_privileges response: {cluster: {privilege: bool}, index: {pattern: {privilege: bool}}}
+fn missing_privileges(body: &Value) -> Vec<String> {
+    let mut missing = vec![];
+
+    if let Some(cluster) = body["cluster"].as_object() {
+        for (privilege, granted) in cluster {
+            if granted.as_bool() != Some(true) {
+                missing.push(format!("cluster {}", privilege));
+            }
+        }
+    }
+    if let Some(indices) = body["index"].as_object() {
+        for (index, privileges) in indices {
+            for (privilege, granted) in privileges.as_object().into_iter().flatten() {
+                if granted.as_bool() != Some(true) {
+                    missing.push(format!("{} on {}", privilege, index));
+                }
+            }
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("8.13.0"), Some((8, 13)));
+        assert_eq!(parse_version("8.14.0-SNAPSHOT"), Some((8, 14)));
+        assert_eq!(parse_version("unknown"), None);
+        assert!(parse_version("7.9.3").unwrap() < MIN_VERSION);
+        assert!(parse_version("7.11.2").unwrap() >= MIN_VERSION);
+        assert!(parse_version("7.11.2").unwrap() < BULK_MIN_VERSION);
+    }
+
+    #[test]
+    fn test_mapping_problems_name_index_and_field() {
+        let body = json!({
+            ".ds-logs-fim.event-default-2024.05.01-000001": {"mappings": {
+                "file.uri": {"full_name": "file.uri", "mapping": {"uri": {"type": "keyword"}}},
+                "@timestamp": {"full_name": "@timestamp", "mapping": {"@timestamp": {"type": "date"}}}
+            }},
+            "fim-broken": {"mappings": {
+                "file.uri": {"full_name": "file.uri", "mapping": {"uri": {"type": "text"}}}
+            }}
+        });
+
+        assert_eq!(
+            mapping_problems(&body),
+            vec![
+                "fim-broken: file.uri is text, expected keyword".to_string(),
+                "fim-broken: @timestamp is not mapped".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_privileges() {
+        let body = json!({
+            "username": "condenser",
+            "has_all_requested": false,
+            "cluster": {"monitor": true},
+            "index": {".ds-logs-fim.event-default*": {"read": true, "delete": false}}
+        });
+
+        assert_eq!(
+            missing_privileges(&body),
+            vec!["delete on .ds-logs-fim.event-default*".to_string()]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration};
+
+use crate::aggs::AggStats;
+use crate::delete_records::DeleteStats;
+use crate::elastic::SharedClient;
+use crate::supervisor::{unix_now, SupervisorStats, WorkerState};
+
+// /healthz: 503 when a worker stopped making progress, restarting the process is the only fix
+// /readyz: 503 as well when a worker is down or elasticsearch can not be reached
+
+pub const AGGREGATION_WORKER: &str = "Aggregation";
+pub const DELETE_WORKER: &str = "Delete";
+
+// how long a ping may take before elasticsearch counts as unreachable
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Probes {
+    pub client: SharedClient,
+    pub aggs: Arc<AggStats>,
+    pub deletes: Arc<DeleteStats>,
+    pub supervisor: Arc<SupervisorStats>,
+    // seconds a worker may go without progress: its own sleep / timeout plus stall_timeout
+    pub aggregation_window: u64,
+    pub delete_window: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProbeReport {
+    pub ok: bool,
+    pub workers: BTreeMap<&'static str, WorkerReport>,
+    // only pinged for readiness, liveness must answer without waiting on the cluster
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elasticsearch: Option<ElasticReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerReport {
+    pub running: bool,
+    pub stalled: bool,
+    pub restarts: u64,
+    // rfc3339, None until the worker made progress once
+    pub last_progress: Option<String>,
+    pub seconds_since_progress: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ElasticReport {
+    pub reachable: bool,
+    pub error: Option<String>,
+}
+
+impl Probes {
+    // an unreachable cluster is not fixed by a restart, so it is not asked
+    pub fn liveness(&self) -> ProbeReport {
+        let workers = self.workers();
+        let ok = workers.values().all(|worker| !worker.stalled);
+        ProbeReport {
+            ok,
+            workers,
+            elasticsearch: None,
+        }
+    }
+
+    pub async fn readiness(&self) -> ProbeReport {
+        let workers = self.workers();
+        let elasticsearch = self.ping().await;
+        let ok = elasticsearch.reachable
+            && workers
+                .values()
+                .all(|worker| worker.running && !worker.stalled);
+        ProbeReport {
+            ok,
+            workers,
+            elasticsearch: Some(elasticsearch),
+        }
+    }
+
+    fn workers(&self) -> BTreeMap<&'static str, WorkerReport> {
+        let now = unix_now();
+        self.supervisor
+            .workers()
+            .into_iter()
+            .map(|(name, state)| {
+                let (last_progress, window) = if name == AGGREGATION_WORKER {
+                    (&self.aggs.last_progress, self.aggregation_window)
+                } else {
+                    (&self.deletes.last_progress, self.delete_window)
+                };
+                let report =
+                    worker_report(&state, last_progress.load(Ordering::Relaxed), window, now);
+                (name, report)
+            })
+            .collect()
+    }
+
+    async fn ping(&self) -> ElasticReport {
+        let client = self.client.get();
+        let error = match timeout(PING_TIMEOUT, client.ping().send()).await {
+            Ok(Ok(response)) if response.status_code().is_success() => None,
+            Ok(Ok(response)) => Some(format!("status {}", response.status_code())),
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!(
+                "no answer within {} seconds",
+                PING_TIMEOUT.as_secs()
+            )),
+        };
+        ElasticReport {
+            reachable: error.is_none(),
+            error,
+        }
+    }
+}
+
+// progress is counted from the last (re)start until the worker reports its own
+fn worker_report(state: &WorkerState, last_progress: u64, window: u64, now: u64) -> WorkerReport {
+    let since = now.saturating_sub(last_progress.max(state.started));
+    WorkerReport {
+        running: state.running,
+        stalled: state.running && since > window,
+        restarts: state.restarts,
+        last_progress: (last_progress > 0)
+            .then(|| chrono::DateTime::from_timestamp(last_progress as i64, 0))
+            .flatten()
+            .map(|time| time.to_rfc3339()),
+        seconds_since_progress: since,
+        last_error: state.last_error.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_stalls_after_window_without_progress() {
+        let state = WorkerState {
+            running: true,
+            restarts: 1,
+            started: 1_000,
+            last_error: Some("Failed to get aggs entries from index".to_string()),
+        };
+
+        // a freshly restarted worker has until started + window
+        let report = worker_report(&state, 0, 60, 1_050);
+        assert!(!report.stalled);
+        assert_eq!(report.last_progress, None);
+
+        let report = worker_report(&state, 1_100, 60, 1_150);
+        assert!(!report.stalled);
+        assert_eq!(report.seconds_since_progress, 50);
+
+        let report = worker_report(&state, 1_100, 60, 1_200);
+        assert!(report.stalled);
+
+        // a worker waiting for its restart is down, not stalled
+        let state = WorkerState {
+            running: false,
+            ..state
+        };
+        assert!(!worker_report(&state, 1_100, 60, 1_200).stalled);
+    }
+}
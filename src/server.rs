@@ -0,0 +1,97 @@
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::metrics::Metrics;
+use crate::probe::{ProbeReport, Probes};
+
+// plain http on listen_addr, meant for a scraper on the same host or network
+
+// binding happens up front, an address that is already in use stops the condenser from starting
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener, Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    log::info!("Serving /metrics, /healthz and /readyz on http://{}", addr);
+    Ok(listener)
+}
+
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, probes: Arc<Probes>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+        let probes = probes.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let metrics = metrics.clone();
+                let probes = probes.clone();
+                async move { Ok::<_, Infallible>(route(request, &metrics, &probes).await) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                log::debug!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn route(
+    request: Request<Incoming>,
+    metrics: &Metrics,
+    probes: &Probes,
+) -> Response<Full<Bytes>> {
+    if request.method() != Method::GET {
+        return text(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed\n".to_string(),
+        );
+    }
+    match request.uri().path() {
+        "/metrics" => text(StatusCode::OK, metrics.render()),
+        "/healthz" => probe(probes.liveness()),
+        "/readyz" => probe(probes.readiness().await),
+        _ => text(StatusCode::NOT_FOUND, "not found\n".to_string()),
+    }
+}
+
+fn probe(report: ProbeReport) -> Response<Full<Bytes>> {
+    let status = if report.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = serde_json::to_string_pretty(&report).unwrap_or_default();
+    let mut response = Response::new(Full::new(Bytes::from(body + "\n")));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn text(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+    );
+    response
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio::time::{sleep, Duration, Instant};
+
+// restart backoff for long-running workers: 1s, 2s, 4s, ... up to 60s
+// a worker that ran for longer than the maximum backoff starts over at 1s
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct SupervisorStats {
+    pub tasks_failed: AtomicU64,
+    pub tasks_panicked: AtomicU64,
+    pub worker_restarts: AtomicU64,
+    // by worker name, for /healthz and /readyz
+    workers: Mutex<BTreeMap<&'static str, WorkerState>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WorkerState {
+    // false while the worker waits for its restart or after it finished
+    pub running: bool,
+    pub restarts: u64,
+    // unix seconds of the last (re)start
+    pub started: u64,
+    pub last_error: Option<String>,
+}
+
+impl SupervisorStats {
+    pub fn workers(&self) -> BTreeMap<&'static str, WorkerState> {
+        self.workers.lock().unwrap().clone()
+    }
+
+    fn update_worker(&self, name: &'static str, update: impl FnOnce(&mut WorkerState)) {
+        update(self.workers.lock().unwrap().entry(name).or_default());
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// owns the short-lived per-event tasks (latest lookups, parsing, delete sends) and
+// the long-running aggregation / delete workers
+pub struct Supervisor {
+    tasks: JoinSet<Result<(), String>>,
+    stats: Arc<SupervisorStats>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            stats: Arc::new(SupervisorStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<SupervisorStats> {
+        self.stats.clone()
+    }
+
+    pub fn spawn_task<F>(&mut self, task: F)
+    where
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    // waits for the next task to finish and records its outcome,
+    // None when there are no tasks
+    pub async fn join_next(&mut self) -> Option<()> {
+        let result = self.tasks.join_next().await?;
+        record(&self.stats, result);
+        Some(())
+    }
+
+    pub fn abort_all(&mut self) {
+        self.tasks.abort_all();
+    }
+
+    // runs a long-running worker and restarts it with backoff whenever it fails or panics,
+    // a worker that returns Ok is done and not restarted
+    pub fn spawn_worker<F, Fut>(&self, name: &'static str, mut factory: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let stats = self.stats.clone();
+
+        tokio::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+
+            loop {
+                let started = Instant::now();
+                stats.update_worker(name, |worker| {
+                    worker.running = true;
+                    worker.started = unix_now();
+                });
+
+                // the worker runs in its own task so a panic can be caught,
+                // the guard makes aborting the supervisor abort the worker as well
+                let mut worker = AbortOnDrop(tokio::spawn(factory()));
+
+                let result = (&mut worker.0).await;
+                stats.update_worker(name, |worker| worker.running = false);
+
+                let error = match result {
+                    Ok(Ok(())) => {
+                        log::info!("{} worker finished", name);
+                        return;
+                    }
+                    Ok(Err(e)) => {
+                        log::error!("{} worker failed: {}", name, e);
+                        e
+                    }
+                    Err(e) if e.is_panic() => {
+                        log::error!("{} worker panicked: {}", name, e);
+                        format!("panicked: {}", e)
+                    }
+                    Err(e) => {
+                        log::info!("{} worker cancelled: {}", name, e);
+                        return;
+                    }
+                };
+
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = MIN_BACKOFF;
+                }
+
+                stats.worker_restarts.fetch_add(1, Ordering::Relaxed);
+                stats.update_worker(name, |worker| {
+                    worker.restarts += 1;
+                    worker.last_error = Some(error);
+                });
+                log::info!(
+                    "Restarting {} worker in {} seconds",
+                    name,
+                    backoff.as_secs()
+                );
+
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        })
+    }
+}
+
+fn record(stats: &SupervisorStats, result: Result<Result<(), String>, JoinError>) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            stats.tasks_failed.fetch_add(1, Ordering::Relaxed);
+            log::error!("Task failed: {}", e);
+        }
+        Err(e) if e.is_panic() => {
+            stats.tasks_panicked.fetch_add(1, Ordering::Relaxed);
+            log::error!("Task panicked: {}", e);
+        }
+        // aborted during shutdown
+        Err(_) => {}
+    }
+}
+
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,62 @@
+use crate::message::DeleteDirective;
+
+// last check before a directive reaches the delete buffer,
+// anything incomplete would otherwise end up as a term / prefix in the delete query
+pub fn validate_directive(directive: &DeleteDirective) -> Result<(), String> {
+    let file_path = directive.file_path.trim();
+
+    if file_path.is_empty() {
+        return Err("Missing file path".to_string());
+    }
+
+    if directive.timestamp.trim().is_empty() {
+        return Err(format!("Missing timestamp for {}", directive.file_path));
+    }
+
+    if let Some(keep) = &directive.keep {
+        if keep.id.trim().is_empty() {
+            return Err(format!("Missing record id for {}", directive.file_path));
+        }
+        if keep.index.trim().is_empty() {
+            return Err(format!("Missing record index for {}", directive.file_path));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::RecordRef;
+
+    fn directive(file_path: &str, keep: Option<(&str, &str)>) -> DeleteDirective {
+        let cascade = keep.is_none();
+        DeleteDirective {
+            file_path: file_path.to_string(),
+            event_type: "change".to_string(),
+            keep: keep.map(|(id, index)| RecordRef {
+                id: id.to_string(),
+                index: index.to_string(),
+            }),
+            cascade,
+            timestamp: "2024-05-01T10:00:00Z".to_string(),
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    fn test_validate_directive() {
+        assert!(validate_directive(&directive("/etc/passwd", Some(("abc", "index")))).is_ok());
+        assert!(validate_directive(&directive("/tmp/dir", None)).is_ok());
+
+        assert!(validate_directive(&directive("", Some(("abc", "index")))).is_err());
+        assert!(validate_directive(&directive("  ", None)).is_err());
+        assert!(validate_directive(&directive("/etc/passwd", Some(("", "index")))).is_err());
+        assert!(validate_directive(&directive("/etc/passwd", Some(("abc", "")))).is_err());
+
+        let mut without_timestamp = directive("/etc/passwd", Some(("abc", "index")));
+        without_timestamp.timestamp = String::new();
+        assert!(validate_directive(&without_timestamp).is_err());
+    }
+}